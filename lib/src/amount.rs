@@ -0,0 +1,319 @@
+// Amounts of money, counted in satoshis.
+// Plain u64 arithmetic silently wraps or panics on overflow, which a peer can trigger with
+// crafted values. All arithmetic on amounts is therefore checked, and no amount is ever
+// larger than MAX_MONEY, so adding a few of them up can not overflow to begin with.
+
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::str::FromStr;
+
+
+pub const SATS_PER_BTC: u64 = 100_000_000;
+
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u64);
+
+
+// no amount can be larger than this, it is the same limit bitcoin has
+
+pub const MAX_MONEY: Amount = Amount(21_000_000 * SATS_PER_BTC);
+
+
+impl Amount {
+
+    pub const ZERO: Amount = Amount(0);
+
+
+    pub const fn from_sat(sats: u64) -> Self {
+
+        Amount(sats)
+    }
+
+
+    // whole bitcoin, panics if that is more than a u64 of satoshis can hold
+
+    pub const fn from_btc(btc: u64) -> Self {
+
+        Amount(btc * SATS_PER_BTC)
+    }
+
+
+    pub const fn to_sat(self) -> u64 {
+
+        self.0
+    }
+
+
+    // whether the amount is within 0..=MAX_MONEY, anything else can not be a valid value
+
+    pub fn is_valid(self) -> bool {
+
+        self <= MAX_MONEY
+    }
+
+
+    // None if the result would be larger than MAX_MONEY
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+
+        self.0.checked_add(other.0).map(Amount).filter(|amount| amount.is_valid())
+    }
+
+
+    // None if the result would be negative
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+
+    pub fn checked_mul(self, factor: u64) -> Option<Amount> {
+
+        self.0.checked_mul(factor).map(Amount).filter(|amount| amount.is_valid())
+    }
+
+
+    pub fn checked_div(self, divisor: u64) -> Option<Amount> {
+
+        self.0.checked_div(divisor).map(Amount)
+    }
+
+
+    pub fn checked_shr(self, bits: u32) -> Option<Amount> {
+
+        self.0.checked_shr(bits).map(Amount)
+    }
+
+
+    // the sum of all amounts, None if the total is larger than MAX_MONEY
+
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+
+        amounts
+            .into_iter()
+            .try_fold(Amount::ZERO, |total, amount| total.checked_add(amount))
+    }
+
+
+    // the amount in satoshis, with the unit
+    // Display shows bitcoin instead
+
+    pub fn to_sat_string(self) -> String {
+
+        format!("{} sat", self.0)
+    }
+}
+
+
+// formats as bitcoin with all eight decimals, e.g. "1.50000000 BTC"
+
+impl fmt::Display for Amount {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+        write!(f, "{}.{:08} BTC", self.0 / SATS_PER_BTC, self.0 % SATS_PER_BTC)
+    }
+}
+
+
+// parses bitcoin, with or without the unit ("1.5", "1.5 BTC"),
+// or satoshis when the unit says so ("150000000 sat", "150000000 sats")
+
+impl FromStr for Amount {
+
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+
+        let s = s.trim();
+
+        let invalid = || format!("invalid amount {}", s);
+
+        let amount = if let Some(sats) = s.strip_suffix("sats").or_else(|| s.strip_suffix("sat")) {
+
+            Amount(sats.trim().parse().map_err(|_| invalid())?)
+
+        } else {
+
+            let btc = s.strip_suffix("BTC").or_else(|| s.strip_suffix("btc")).unwrap_or(s).trim();
+
+            let (whole, fraction) = btc.split_once('.').unwrap_or((btc, ""));
+
+            if whole.is_empty() && fraction.is_empty() {
+
+                return Err(invalid());
+            }
+
+            if fraction.len() > 8 {
+
+                return Err(format!("amount {} has more than 8 decimals", s));
+            }
+
+            if !whole.bytes().chain(fraction.bytes()).all(|byte| byte.is_ascii_digit()) {
+
+                return Err(invalid());
+            }
+
+            let whole: u64 = if whole.is_empty() { 0 } else { whole.parse().map_err(|_| invalid())? };
+
+            // pad the fraction to eight digits, "5" is 50000000 satoshis
+
+            let fraction: u64 = format!("{:0<8}", fraction).parse().map_err(|_| invalid())?;
+
+            whole
+                .checked_mul(SATS_PER_BTC)
+                .and_then(|sats| sats.checked_add(fraction))
+                .map(Amount)
+                .ok_or_else(|| format!("amount {} is larger than the money supply", s))?
+        };
+
+        if !amount.is_valid() {
+
+            return Err(format!("amount {} is larger than the money supply", s));
+        }
+
+        Ok(amount)
+    }
+}
+
+
+// a fee per serialized size, in satoshis per 1000 bytes
+// whole satoshis per byte would be too coarse to tell small transactions apart
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FeeRate(u64);
+
+
+impl FeeRate {
+
+    pub const ZERO: FeeRate = FeeRate(0);
+
+
+    pub const fn from_sat_per_kb(sats: u64) -> Self {
+
+        FeeRate(sats)
+    }
+
+
+    pub const fn to_sat_per_kb(self) -> u64 {
+
+        self.0
+    }
+
+
+    // the rate of paying `fee` for `size` bytes, rounded down
+
+    pub fn new(fee: Amount, size: usize) -> Self {
+
+        let rate = (fee.0 as u128 * 1000) / (size.max(1) as u128);
+
+        FeeRate(u64::try_from(rate).unwrap_or(u64::MAX))
+    }
+
+
+    // the fee for `size` bytes at this rate, rounded up so paying it always meets the rate
+
+    pub fn fee(self, size: usize) -> Amount {
+
+        let fee = (self.0 as u128 * size as u128).div_ceil(1000);
+
+        Amount(u64::try_from(fee).unwrap_or(u64::MAX))
+    }
+
+
+    pub fn saturating_add(self, other: FeeRate) -> FeeRate {
+
+        FeeRate(self.0.saturating_add(other.0))
+    }
+}
+
+
+impl fmt::Display for FeeRate {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+        write!(f, "{} sat/kB", self.0)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    fn parse(s: &str) -> Result<Amount, String> {
+
+        s.parse()
+    }
+
+
+    #[test]
+    fn parses_bitcoin_and_satoshis() {
+
+        assert_eq!(parse("1.5"), Ok(Amount::from_sat(150_000_000)));
+        assert_eq!(parse("1.5 BTC"), Ok(Amount::from_sat(150_000_000)));
+        assert_eq!(parse(" 1.5btc "), Ok(Amount::from_sat(150_000_000)));
+        assert_eq!(parse(".5"), Ok(Amount::from_sat(50_000_000)));
+        assert_eq!(parse("5."), Ok(Amount::from_btc(5)));
+        assert_eq!(parse("0.00000001"), Ok(Amount::from_sat(1)));
+
+        assert_eq!(parse("150000000 sat"), Ok(Amount::from_sat(150_000_000)));
+        assert_eq!(parse("150000000sats"), Ok(Amount::from_sat(150_000_000)));
+        assert_eq!(parse("0 sat"), Ok(Amount::ZERO));
+    }
+
+
+    #[test]
+    fn rejects_malformed_amounts() {
+
+        for s in ["", ".", "BTC", "sat", "-1", "+1", "1e5", "1,5", "1.5.0", "0x10", "1.5 sat", "ten"] {
+
+            assert!(parse(s).is_err(), "{:?} should not parse", s);
+        }
+
+        // satoshis are indivisible
+
+        assert!(parse("0.000000001").is_err());
+    }
+
+
+    #[test]
+    fn rejects_amounts_above_the_money_supply() {
+
+        assert_eq!(parse("21000000"), Ok(MAX_MONEY));
+        assert_eq!(parse("2100000000000000 sat"), Ok(MAX_MONEY));
+
+        assert!(parse("21000000.00000001").is_err());
+        assert!(parse("2100000000000001 sat").is_err());
+
+        // too large for a u64, in satoshis and once converted from bitcoin
+
+        assert!(parse("18446744073709551616 sat").is_err());
+        assert!(parse("184467440738").is_err());
+    }
+
+
+    #[test]
+    fn displays_bitcoin_with_eight_decimals() {
+
+        assert_eq!(Amount::ZERO.to_string(), "0.00000000 BTC");
+        assert_eq!(Amount::from_sat(1).to_string(), "0.00000001 BTC");
+        assert_eq!(Amount::from_sat(150_000_000).to_string(), "1.50000000 BTC");
+        assert_eq!(MAX_MONEY.to_string(), "21000000.00000000 BTC");
+
+        assert_eq!(Amount::from_sat(42).to_sat_string(), "42 sat");
+
+        // what is displayed parses back to the same amount
+
+        for amount in [Amount::ZERO, Amount::from_sat(1), Amount::from_sat(123_456_789), MAX_MONEY] {
+
+            assert_eq!(parse(&amount.to_string()), Ok(amount));
+            assert_eq!(parse(&amount.to_sat_string()), Ok(amount));
+        }
+    }
+}
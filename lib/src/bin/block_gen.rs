@@ -1,4 +1,5 @@
 use lib::crypto::PrivateKey;
+use lib::params::{ChainParams, Network};
 use lib::sha256::Hash;
 use lib::types:: {
     Block, BlockHeader, Transaction, TransactionOutput,
@@ -6,7 +7,6 @@ use lib::types:: {
 
 use lib::util::{MerkleRoot, Saveable};
 use chrono::Utc;
-use uuid::Uuid;
 use std::env;
 use std::process::exit;
 
@@ -22,21 +22,34 @@ fn main() {
 
     } else {
 
-        eprintln!("Usage: block_gen <block_file>");
+        eprintln!("Usage: block_gen <block_file> [mainnet|testnet|regtest]");
 
         // code of 1 typically indicates that the program encountered an error or terminated unsuccessfully.
         exit(1);
 
     };
 
+    let network: Network = match env::args().nth(2) {
+
+        Some(network) => network.parse().unwrap_or_else(|e| {
+
+            eprintln!("{}", e);
+            exit(1);
+        }),
+
+        None => Network::Mainnet,
+    };
+
+    let params = ChainParams::for_network(network);
+
     let private_key = PrivateKey::new_key();
 
-    let transaction = vec![Transaction::new(
+    let transaction = vec![Transaction::new_coinbase(
         
+        0,
         vec![],
         vec![TransactionOutput {
-            unique_id: Uuid::new_v4(),
-            value: lib::INITIAL_REWARD * 10u64.pow(8),
+            value: params.block_reward(0),
             pubkey: private_key.public_key(),
         }], 
 
@@ -50,7 +63,7 @@ fn main() {
             0,
             Hash::zero(),
             merkle_root,
-            lib::MIN_TARGET,
+            params.min_target.to_compact(),
 
         ),
         transaction
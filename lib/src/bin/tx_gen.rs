@@ -1,9 +1,9 @@
 
 
 use lib::crypto::PrivateKey;
+use lib::params::{ChainParams, Network};
 use lib::types::{Transaction, TransactionOutput};
 use lib::util::Saveable;
-use uuid::Uuid;
 use std::env;
 use std::process::exit;
 
@@ -18,22 +18,35 @@ fn main() {
 
         } else {
 
-            eprintln!("Usage: tx_gen <tx_file>");
+            eprintln!("Usage: tx_gen <tx_file> [mainnet|testnet|regtest]");
 
             exit(1);
         };
 
 
 
+    let network: Network = match env::args().nth(2) {
+
+        Some(network) => network.parse().unwrap_or_else(|e| {
+
+            eprintln!("{}", e);
+            exit(1);
+        }),
+
+        None => Network::Mainnet,
+    };
+
+    let params = ChainParams::for_network(network);
+
     let private_key = PrivateKey::new_key();
 
-    let transaction = Transaction::new(
+    let transaction = Transaction::new_coinbase(
 
+    0,
     vec![],
     vec![TransactionOutput {
 
-        unique_id: Uuid::new_v4(),
-        value:     lib::INITIAL_REWARD * 10u64.pow(8),
+        value:     params.block_reward(0),
         pubkey:    private_key.public_key(),
     }],
     );
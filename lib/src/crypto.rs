@@ -33,21 +33,23 @@ pub struct Signature(pub ECDSASignature<Secp256k1>);
 
 impl Signature {
 
-    pub fn sign_output(output_hash: &Hash, private_key: &PrivateKey) -> Self {
+    // sign a signature hash, see Transaction::signature_hash()
+
+    pub fn sign_hash(hash: &Hash, private_key: &PrivateKey) -> Self {
 
 
         let signing_key = &private_key.0;
 
-        let signature = signing_key.sign(&output_hash.as_bytes());
+        let signature = signing_key.sign(&hash.as_bytes());
         
         Signature(signature)
     }
 
 
 
-    pub fn verify( &self, output_hash: &Hash, public_key: &PublicKey, ) -> bool {
+    pub fn verify( &self, hash: &Hash, public_key: &PublicKey, ) -> bool {
 
-        public_key.0.verify(&output_hash.as_bytes(), &self.0).is_ok()
+        public_key.0.verify(&hash.as_bytes(), &self.0).is_ok()
 
         }
         
@@ -60,6 +62,16 @@ impl Signature {
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq,)]
 pub struct PublicKey(VerifyingKey<Secp256k1>);
 
+impl PublicKey {
+
+    // parse a public key from its SEC1 encoding (the 0x02/0x03/0x04 prefixed point)
+
+    pub fn from_sec1_bytes(bytes: &[u8]) -> Option<Self> {
+
+        VerifyingKey::from_sec1_bytes(bytes).ok().map(PublicKey)
+    }
+}
+
 
 // save and load as PEM 
 // PEM (Privacy-Enhanced Mail) is a widely used format for encoding cryptographic keys, 
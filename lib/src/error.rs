@@ -1,5 +1,8 @@
 use thiserror::Error;
 
+use crate::mempool::ReplacementRejection;
+use crate::validation::RuleViolation;
+
 #[derive(Error, Debug)]
 pub enum BtcError {
 
@@ -33,11 +36,38 @@ pub enum BtcError {
     #[error("Invalid private key")]
     InvalidPrivateKey,
 
+    #[error("Storage error: {0}")]
+    Storage(#[from] std::io::Error),
+
+    // a block or transaction broke one of the rules, see validation.rs
+    #[error("{0}")]
+    RuleViolation(#[from] RuleViolation),
+
+    // a transaction conflicting with the mempool did not pay enough to replace what is there
+    #[error("replacement rejected: {0}")]
+    ReplacementRejected(#[from] ReplacementRejection),
+
     
 
 
 
 
+}
+
+impl BtcError {
+
+    // place a rule violation found in a transaction on its own at its index in the block,
+    // other errors are left alone
+
+    pub fn in_transaction(self, transaction: usize) -> Self {
+
+        match self {
+
+            BtcError::RuleViolation(violation) => BtcError::RuleViolation(violation.in_transaction(transaction)),
+
+            error => error,
+        }
+    }
 }
 
 
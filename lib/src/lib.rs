@@ -7,36 +7,70 @@ construct_uint!{
     pub struct U256(4);
 }
 
-// initial rewa5rd in bitcoin  - multiply by 10^8 to get satoshis
+// compact "bits" encoding of targets, the same one bitcoin uses in its block headers:
+// the highest byte is the length of the number in bytes, the lower three bytes are its
+// most significant bytes. Only those three bytes survive, so to_compact rounds down.
 
-pub const INITIAL_REWARD: u64 = 50;
+impl U256 {
 
-// halving interval in blocks 
+    pub fn to_compact(&self) -> u32 {
 
-pub const HALVING_INTERVAL: u64 = 210;
+        let mut size = self.bits().div_ceil(8);
 
-// Ideal block time in seconds  @note actual is average of 10 minutes
+        let mut mantissa = if size <= 3 {
 
-pub const IDEAL_BLOCK_TIME: u64 = 10;
+            (self.low_u64() << (8 * (3 - size))) as u32
 
-// minimum target
+        } else {
 
-pub const MIN_TARGET: U256 = U256([
-    0xFFFF_FFFF_FFFF_FFFF,
-    0xFFFF_FFFF_FFFF_FFFF,
-    0xFFFF_FFFF_FFFF_FFFF,
-    0x0000_FFFF_FFFF_FFFF,
-    ]);
+            (*self >> (8 * (size - 3))).low_u32()
+        };
 
-// Difficulty update interval in blocks
+        // the mantissa is signed, if its top bit is set we move it down a byte
 
-pub const  DIFFICULTY_UPDATE_INTERVAL: u64 = 50;
+        if mantissa & 0x0080_0000 != 0 {
 
-// maximum mempool transaction age in seconds
+            mantissa >>= 8;
+            size += 1;
+        }
 
-pub const MAX_MEMPOOL_TRANSACTION_AGE: u64 = 600;
+        mantissa | ((size as u32) << 24)
+    }
 
 
+    // None if the bits encode a negative number or one that does not fit in 256 bits
+
+    pub fn from_compact(bits: u32) -> Option<Self> {
+
+        let size = (bits >> 24) as usize;
+        let mantissa = bits & 0x007f_ffff;
+
+        if mantissa == 0 {
+
+            return Some(U256::zero());
+        }
+
+        if bits & 0x0080_0000 != 0 {
+
+            return None;
+        }
+
+        if size > 34 || (mantissa > 0xff && size > 33) || (mantissa > 0xffff && size > 32) {
+
+            return None;
+        }
+
+        if size <= 3 {
+
+            Some(U256::from(mantissa >> (8 * (3 - size))))
+
+        } else {
+
+            Some(U256::from(mantissa) << (8 * (size - 3)))
+        }
+    }
+}
+
 
 pub mod sha256;
 pub mod types;
@@ -44,5 +78,92 @@ pub mod util;
 pub mod crypto;
 pub mod error;
 pub mod network;
+pub mod store;
+pub mod utxo;
+pub mod params;
+pub mod validation;
+pub mod amount;
+pub mod mempool;
+pub mod template;
+
+
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn compact_round_trips_targets() {
+
+        // the mainnet minimum target, the same as bitcoin's
+
+        let target = U256::from(0xffff) << 208;
 
+        assert_eq!(target.to_compact(), 0x1d00ffff);
+        assert_eq!(U256::from_compact(0x1d00ffff), Some(target));
 
+        assert_eq!(U256::zero().to_compact(), 0);
+        assert_eq!(U256::from_compact(0), Some(U256::zero()));
+    }
+
+
+    #[test]
+    fn compact_rounds_down_to_three_bytes() {
+
+        let target = U256::from(0x12_3456_789a_u64);
+
+        assert_eq!(target.to_compact(), 0x05123456);
+        assert_eq!(U256::from_compact(0x05123456), Some(U256::from(0x12_3456_0000_u64)));
+    }
+
+
+    #[test]
+    fn compact_with_size_of_three_bytes_or_less() {
+
+        // the mantissa is shifted down, the bytes below the size are dropped
+
+        assert_eq!(U256::from_compact(0x01123456), Some(U256::from(0x12)));
+        assert_eq!(U256::from_compact(0x02123456), Some(U256::from(0x1234)));
+        assert_eq!(U256::from_compact(0x03123456), Some(U256::from(0x123456)));
+
+        assert_eq!(U256::from(0x12).to_compact(), 0x01120000);
+        assert_eq!(U256::from(0x1234).to_compact(), 0x02123400);
+        assert_eq!(U256::from(0x123456).to_compact(), 0x03123456);
+
+        // a top bit in the mantissa would make it negative, so it takes one more byte
+
+        assert_eq!(U256::from(0x80).to_compact(), 0x02008000);
+        assert_eq!(U256::from_compact(0x02008000), Some(U256::from(0x80)));
+    }
+
+
+    #[test]
+    fn compact_rejects_negative_numbers() {
+
+        assert_eq!(U256::from_compact(0x04923456), None);
+        assert_eq!(U256::from_compact(0x01fedcba), None);
+
+        // a negative zero is still zero
+
+        assert_eq!(U256::from_compact(0x04800000), Some(U256::zero()));
+    }
+
+
+    #[test]
+    fn compact_rejects_numbers_above_256_bits() {
+
+        assert_eq!(U256::from_compact(0xff123456), None);
+        assert_eq!(U256::from_compact(0x23000001), None);
+        assert_eq!(U256::from_compact(0x22000100), None);
+        assert_eq!(U256::from_compact(0x21010000), None);
+
+        // the largest sizes that still fit, given how many bytes of the mantissa are used
+
+        assert_eq!(U256::from_compact(0x22000001), Some(U256::one() << 248));
+        assert_eq!(U256::from_compact(0x21000100), Some(U256::one() << 248));
+        assert_eq!(U256::from_compact(0x20010000), Some(U256::one() << 248));
+    }
+}
@@ -0,0 +1,1051 @@
+// Transactions waiting to be included in a block.
+// Entries are indexed by their id and ordered by fee rate, the fee per serialized byte,
+// because block space is what a miner sells: a small transaction paying a modest fee
+// is worth more to a block than a large one paying a little more.
+// The mempool holds a bounded number of bytes. Once it is full the lowest fee rates are evicted
+// and the minimum fee rate for new transactions goes up, so the ones that were just evicted
+// can not come straight back in. That minimum halves every few hours until it is gone again.
+// A transaction can spend outputs of other mempool transactions, its parents. Chains of
+// unconfirmed transactions are limited in length and size, and a transaction that leaves the
+// mempool for any other reason than being mined takes its descendants with it.
+// A transaction spending the same output as a mempool transaction replaces it, if it pays
+// more for it: a higher fee rate than every transaction it conflicts with, and enough fee
+// to cover everything it evicts plus its own relay. Otherwise it is rejected, with the reason.
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::amount::{Amount, FeeRate, MAX_MONEY};
+use crate::error::BtcError;
+use crate::sha256::Hash;
+use crate::types::{OutPoint, Transaction};
+use crate::utxo::{UtxoEntry, UtxoView};
+use crate::validation::{Rule, RuleViolation};
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Result as IoResult;
+
+
+// what the minimum fee rate is raised to above the fee rate of an evicted transaction,
+// also the rate below which the minimum drops back to zero
+
+pub const INCREMENTAL_RELAY_FEE: FeeRate = FeeRate::from_sat_per_kb(1000);
+
+// how long it takes the minimum fee rate to halve after an eviction
+
+pub const MIN_FEE_HALFLIFE: Duration = Duration::hours(12);
+
+// a mempool transaction, together with its unconfirmed ancestors, can be at most this many
+// transactions and this many bytes, and the same goes for it together with its descendants
+
+pub const MAX_ANCESTORS: usize = 25;
+pub const MAX_ANCESTOR_SIZE: usize = 101_000;
+
+pub const MAX_DESCENDANTS: usize = 25;
+pub const MAX_DESCENDANT_SIZE: usize = 101_000;
+
+// a replacement can evict at most this many transactions, descendants included
+
+pub const MAX_REPLACEMENTS: usize = 100;
+
+
+// why a transaction was not allowed to replace the ones it conflicts with
+
+#[derive(Error, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ReplacementRejection {
+
+    #[error("it spends an output of a transaction it would replace")]
+    SpendsConflictingTransaction,
+
+    #[error("it would evict {count} transactions, at most {max} are allowed")]
+    TooManyReplacements { count: usize, max: usize },
+
+    #[error("its fee rate {fee_rate} is not higher than {conflicting} of a transaction it would replace")]
+    FeeRateTooLow { fee_rate: FeeRate, conflicting: FeeRate },
+
+    #[error("its fee {fee} is less than the {required} needed to replace")]
+    FeeTooLow { fee: Amount, required: Amount },
+}
+
+
+// why a submitted transaction did not make it into the mempool, sent back to the client
+// replacements get their own reason, anything else is the rule it broke
+
+#[derive(Error, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransactionRejection {
+
+    #[error("replacement rejected: {0}")]
+    Replacement(ReplacementRejection),
+
+    #[error("{0}")]
+    Invalid(String),
+}
+
+impl From<BtcError> for TransactionRejection {
+
+    fn from(error: BtcError) -> Self {
+
+        match error {
+
+            BtcError::ReplacementRejected(rejection) => TransactionRejection::Replacement(rejection),
+
+            error => TransactionRejection::Invalid(error.to_string()),
+        }
+    }
+}
+
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MempoolEntry {
+
+    pub transaction: Transaction,
+
+    // inputs minus outputs, worked out when the transaction was accepted
+    pub fee: Amount,
+
+    pub size: usize,
+
+    // when the transaction arrived
+    pub time: DateTime<Utc>,
+}
+
+impl MempoolEntry {
+
+    pub fn new(transaction: Transaction, fee: Amount, time: DateTime<Utc>) -> Self {
+
+        let size = transaction.serialized_size();
+
+        MempoolEntry {
+            transaction,
+            fee,
+            size,
+            time,
+        }
+    }
+
+
+    pub fn txid(&self) -> Hash {
+
+        self.transaction.hash()
+    }
+
+
+    pub fn fee_rate(&self) -> FeeRate {
+
+        FeeRate::new(self.fee, self.size)
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct Mempool {
+
+    entries: HashMap<Hash, MempoolEntry>,
+
+    // every entry, lowest fee rate first
+    by_fee_rate: BTreeSet<(FeeRate, Hash)>,
+
+    // which entry spends an output, two entries never spend the same one
+    spends: HashMap<OutPoint, Hash>,
+
+    // the entries whose outputs an entry spends, and the ones spending its outputs
+    parents: HashMap<Hash, HashSet<Hash>>,
+    children: HashMap<Hash, HashSet<Hash>>,
+
+    // total size of all entries in bytes
+    size: usize,
+    max_size: usize,
+
+    // the minimum fee rate right after the last eviction, see min_fee_rate
+    rolling_min_fee_rate: FeeRate,
+    last_eviction: DateTime<Utc>,
+}
+
+impl Mempool {
+
+    pub fn new(max_size: usize) -> Self {
+
+        Mempool {
+            entries: HashMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            spends: HashMap::new(),
+            parents: HashMap::new(),
+            children: HashMap::new(),
+            size: 0,
+            max_size,
+            rolling_min_fee_rate: FeeRate::ZERO,
+            last_eviction: Utc::now(),
+        }
+    }
+
+
+    pub fn len(&self) -> usize {
+
+        self.entries.len()
+    }
+
+
+    pub fn is_empty(&self) -> bool {
+
+        self.entries.is_empty()
+    }
+
+
+    // total size of all transactions in bytes
+
+    pub fn size(&self) -> usize {
+
+        self.size
+    }
+
+
+    pub fn max_size(&self) -> usize {
+
+        self.max_size
+    }
+
+
+    pub fn get(&self, txid: &Hash) -> Option<&MempoolEntry> {
+
+        self.entries.get(txid)
+    }
+
+
+    pub fn contains(&self, txid: &Hash) -> bool {
+
+        self.entries.contains_key(txid)
+    }
+
+
+    // the id of the transaction spending `outpoint`, if any
+
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<Hash> {
+
+        self.spends.get(outpoint).copied()
+    }
+
+
+    // the entries whose outputs `txid` spends
+
+    pub fn parents(&self, txid: &Hash) -> impl Iterator<Item = &Hash> {
+
+        self.parents.get(txid).into_iter().flatten()
+    }
+
+
+    // every entry `txid` depends on, directly or through other entries, not including itself
+
+    pub fn ancestors(&self, txid: &Hash) -> HashSet<Hash> {
+
+        self.walk(self.parents(txid).copied().collect(), &self.parents)
+    }
+
+
+    // every entry depending on `txid`, not including itself
+
+    pub fn descendants(&self, txid: &Hash) -> HashSet<Hash> {
+
+        self.walk(self.children.get(txid).into_iter().flatten().copied().collect(), &self.children)
+    }
+
+
+    // everything reachable from `start` following `links`
+
+    fn walk(&self, start: Vec<Hash>, links: &HashMap<Hash, HashSet<Hash>>) -> HashSet<Hash> {
+
+        let mut found = HashSet::new();
+        let mut queue = start;
+
+        while let Some(txid) = queue.pop() {
+
+            if found.insert(txid) {
+
+                queue.extend(links.get(&txid).into_iter().flatten().copied());
+            }
+        }
+
+        found
+    }
+
+
+    // the entries spending the same outputs as `transaction`, with all their descendants
+    // all of them have to go for `transaction` to be accepted
+
+    pub fn conflicts(&self, transaction: &Transaction) -> HashSet<Hash> {
+
+        let mut conflicts = HashSet::new();
+
+        for input in &transaction.inputs {
+
+            if let Some(txid) = self.spender(&input.previous_output) {
+
+                conflicts.extend(self.descendants(&txid));
+                conflicts.insert(txid);
+            }
+        }
+
+        conflicts
+    }
+
+
+    // check that `entry` pays enough to replace the entries in `conflicts`, see conflicts()
+
+    pub fn check_replacement(&self, entry: &MempoolEntry, conflicts: &HashSet<Hash>) -> Result<(), ReplacementRejection> {
+
+        // the outputs it spends would be gone along with what it replaces
+
+        if entry.transaction.inputs.iter().any(|input| conflicts.contains(&input.previous_output.txid)) {
+
+            return Err(ReplacementRejection::SpendsConflictingTransaction);
+        }
+
+        if conflicts.len() > MAX_REPLACEMENTS {
+
+            return Err(ReplacementRejection::TooManyReplacements {
+                count: conflicts.len(),
+                max: MAX_REPLACEMENTS,
+            });
+        }
+
+        // a better fee rate than every transaction spending the same outputs,
+        // otherwise miners would rather have what is already there
+
+        let direct_conflicts = entry
+            .transaction
+            .inputs
+            .iter()
+            .filter_map(|input| self.spender(&input.previous_output));
+
+        for txid in direct_conflicts {
+
+            let conflicting = self.entries[&txid].fee_rate();
+
+            if entry.fee_rate() <= conflicting {
+
+                return Err(ReplacementRejection::FeeRateTooLow {
+                    fee_rate: entry.fee_rate(),
+                    conflicting,
+                });
+            }
+        }
+
+        // and it pays for the bandwidth of everything it evicts, as well as its own
+
+        let replaced_fees = Amount::checked_sum(conflicts.iter().map(|txid| self.entries[txid].fee));
+
+        let required = replaced_fees.and_then(|fees| fees.checked_add(INCREMENTAL_RELAY_FEE.fee(entry.size)));
+
+        match required {
+
+            Some(required) if entry.fee >= required => Ok(()),
+
+            required => Err(ReplacementRejection::FeeTooLow {
+                fee: entry.fee,
+                required: required.unwrap_or(MAX_MONEY),
+            }),
+        }
+    }
+
+
+    // check that adding a transaction of `size` bytes spending `transaction`'s inputs keeps
+    // every chain of unconfirmed transactions within the ancestor and descendant limits
+    // the entries in `replaced` are about to be removed and do not count
+
+    pub fn check_chain_limits(&self, transaction: &Transaction, size: usize, replaced: &HashSet<Hash>) -> Result<(), RuleViolation> {
+
+        let parents: Vec<Hash> = transaction
+            .inputs
+            .iter()
+            .map(|input| input.previous_output.txid)
+            .filter(|txid| self.contains(txid))
+            .collect();
+
+        let ancestors = self.walk(parents, &self.parents);
+
+        let ancestor_size: usize = ancestors.iter().map(|txid| self.entries[txid].size).sum();
+
+        if ancestors.len() + 1 > MAX_ANCESTORS {
+
+            return Err(RuleViolation::standalone(Rule::Policy, "too many unconfirmed ancestors"));
+        }
+
+        if ancestor_size + size > MAX_ANCESTOR_SIZE {
+
+            return Err(RuleViolation::standalone(Rule::Policy, "unconfirmed ancestors are too large"));
+        }
+
+        // every ancestor gets one more descendant
+
+        for ancestor in &ancestors {
+
+            let descendants: HashSet<Hash> = self.descendants(ancestor).difference(replaced).copied().collect();
+
+            let descendant_size: usize = descendants.iter().map(|txid| self.entries[txid].size).sum();
+
+            if descendants.len() + 2 > MAX_DESCENDANTS {
+
+                return Err(RuleViolation::standalone(Rule::Policy, "too many unconfirmed descendants"));
+            }
+
+            if self.entries[ancestor].size + descendant_size + size > MAX_DESCENDANT_SIZE {
+
+                return Err(RuleViolation::standalone(Rule::Policy, "unconfirmed descendants are too large"));
+            }
+        }
+
+        Ok(())
+    }
+
+
+    // every entry, parents before their children, so adding them one by one in this order works
+
+    pub fn in_dependency_order(&self) -> Vec<&MempoolEntry> {
+
+        let mut entries: Vec<(usize, &MempoolEntry)> = self
+            .iter()
+            .map(|entry| (self.ancestors(&entry.txid()).len(), entry))
+            .collect();
+
+        // a parent always has fewer ancestors than its child
+
+        entries.sort_by_key(|(ancestors, _)| *ancestors);
+
+        entries.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+
+    // every entry, highest fee rate first
+
+    pub fn iter(&self) -> impl Iterator<Item = &MempoolEntry> {
+
+        self.by_fee_rate.iter().rev().map(|(_, txid)| &self.entries[txid])
+    }
+
+
+    // the fee rate a new transaction has to pay at least
+    // zero until the mempool fills up, then it decays from the last eviction
+
+    pub fn min_fee_rate(&self) -> FeeRate {
+
+        self.min_fee_rate_at(Utc::now())
+    }
+
+
+    pub fn min_fee_rate_at(&self, now: DateTime<Utc>) -> FeeRate {
+
+        let halvings = (now - self.last_eviction).num_seconds() / MIN_FEE_HALFLIFE.num_seconds();
+
+        let rate = u32::try_from(halvings)
+            .ok()
+            .and_then(|halvings| self.rolling_min_fee_rate.to_sat_per_kb().checked_shr(halvings))
+            .unwrap_or(0);
+
+        if rate < INCREMENTAL_RELAY_FEE.to_sat_per_kb() / 2 {
+
+            return FeeRate::ZERO;
+        }
+
+        FeeRate::from_sat_per_kb(rate)
+    }
+
+
+    // add an entry, then evict the lowest fee rates until the mempool fits its size again
+    // returns the evicted entries, which can include the new one
+    // the caller makes sure the entry does not conflict with one that is already in the mempool
+
+    pub(crate) fn insert(&mut self, entry: MempoolEntry) -> Vec<MempoolEntry> {
+
+        let txid = entry.txid();
+
+        let mut parents = HashSet::new();
+
+        for input in &entry.transaction.inputs {
+
+            self.spends.insert(input.previous_output, txid);
+
+            if self.entries.contains_key(&input.previous_output.txid) {
+
+                parents.insert(input.previous_output.txid);
+            }
+        }
+
+        for parent in &parents {
+
+            self.children.entry(*parent).or_default().insert(txid);
+        }
+
+        self.parents.insert(txid, parents);
+
+        self.by_fee_rate.insert((entry.fee_rate(), txid));
+        self.size += entry.size;
+        self.entries.insert(txid, entry);
+
+        self.trim()
+    }
+
+
+    // remove a single entry, its children stay and from now on spend outputs outside the mempool
+    // this is only right when the entry was mined, otherwise use remove_with_descendants
+
+    pub(crate) fn remove(&mut self, txid: &Hash) -> Option<MempoolEntry> {
+
+        let entry = self.entries.remove(txid)?;
+
+        for input in &entry.transaction.inputs {
+
+            self.spends.remove(&input.previous_output);
+        }
+
+        for parent in self.parents.remove(txid).unwrap_or_default() {
+
+            if let Some(children) = self.children.get_mut(&parent) {
+
+                children.remove(txid);
+            }
+        }
+
+        for child in self.children.remove(txid).unwrap_or_default() {
+
+            if let Some(parents) = self.parents.get_mut(&child) {
+
+                parents.remove(txid);
+            }
+        }
+
+        self.by_fee_rate.remove(&(entry.fee_rate(), *txid));
+        self.size -= entry.size;
+
+        Some(entry)
+    }
+
+
+    // remove an entry and everything spending its outputs, returning all of them
+
+    pub(crate) fn remove_with_descendants(&mut self, txid: &Hash) -> Vec<MempoolEntry> {
+
+        if !self.contains(txid) {
+
+            return vec![];
+        }
+
+        let mut txids = self.descendants(txid);
+
+        txids.insert(*txid);
+
+        txids.iter().filter_map(|txid| self.remove(txid)).collect()
+    }
+
+
+    // remove every entry `predicate` returns true for, and their descendants, returning them
+
+    pub(crate) fn remove_where<F: FnMut(&MempoolEntry) -> bool>(&mut self, mut predicate: F) -> Vec<MempoolEntry> {
+
+        let txids: Vec<Hash> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| predicate(entry))
+            .map(|(txid, _)| *txid)
+            .collect();
+
+        txids.iter().flat_map(|txid| self.remove_with_descendants(txid)).collect()
+    }
+
+
+    // evict the lowest fee rates, with their descendants, until we are within max_size,
+    // raising the minimum fee rate above them
+
+    fn trim(&mut self) -> Vec<MempoolEntry> {
+
+        let mut evicted = vec![];
+
+        while self.size > self.max_size {
+
+            let Some(&(fee_rate, txid)) = self.by_fee_rate.first() else {
+
+                break;
+            };
+
+            let now = Utc::now();
+
+            self.rolling_min_fee_rate = self
+                .min_fee_rate_at(now)
+                .max(fee_rate.saturating_add(INCREMENTAL_RELAY_FEE));
+
+            self.last_eviction = now;
+
+            evicted.extend(self.remove_with_descendants(&txid));
+        }
+
+        evicted
+    }
+}
+
+
+// what a new mempool transaction can spend: the unspent outputs of `utxos`
+// and the outputs of mempool transactions, which would be created in the block at `height`
+
+pub struct MempoolView<'a, V: UtxoView> {
+
+    pub utxos: &'a V,
+    pub mempool: &'a Mempool,
+    pub height: u64,
+}
+
+impl<V: UtxoView> UtxoView for MempoolView<'_, V> {
+
+    fn get_entry(&self, outpoint: &OutPoint) -> IoResult<Option<UtxoEntry>> {
+
+        if let Some(entry) = self.utxos.get_entry(outpoint)? {
+
+            return Ok(Some(entry));
+        }
+
+        let output = self
+            .mempool
+            .get(&outpoint.txid)
+            .and_then(|entry| entry.transaction.outputs.get(outpoint.vout as usize));
+
+        Ok(output.map(|output| UtxoEntry {
+            output: output.clone(),
+            height: self.height,
+            coinbase: false,
+        }))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::types::{SigHashType, TransactionOutput};
+
+
+    // an output of a made-up confirmed transaction, the mempool does not look at what an entry spends
+
+    fn coin(n: u64) -> OutPoint {
+
+        OutPoint { txid: Hash::hash(&n), vout: 0 }
+    }
+
+
+    // an entry spending `spends` into `outputs` outputs and paying `fee`
+    // the signatures are real, but nothing here checks them or the amounts
+
+    fn entry(spends: &[OutPoint], outputs: usize, fee: u64) -> MempoolEntry {
+
+        let key = PrivateKey::new_key();
+
+        let outputs = (0..outputs)
+            .map(|_| TransactionOutput { value: Amount::from_sat(1_000), pubkey: key.public_key() })
+            .collect();
+
+        let spends: Vec<(OutPoint, &PrivateKey)> = spends.iter().map(|outpoint| (*outpoint, &key)).collect();
+
+        let transaction = Transaction::new_signed(&spends, outputs, SigHashType::ALL).unwrap();
+
+        MempoolEntry::new(transaction, Amount::from_sat(fee), Utc::now())
+    }
+
+
+    fn txids(entries: &[MempoolEntry]) -> HashSet<Hash> {
+
+        entries.iter().map(|entry| entry.txid()).collect()
+    }
+
+
+    #[test]
+    fn orders_entries_by_fee_rate() {
+
+        let mut mempool = Mempool::new(1_000_000);
+
+        let low = entry(&[coin(0)], 1, 1_000);
+        let high = entry(&[coin(1)], 1, 100_000);
+        let middle = entry(&[coin(2)], 1, 10_000);
+
+        // the highest fee, but spread over so many bytes that it is worth less than `middle`
+
+        let large = entry(&[coin(3)], 100, 150_000);
+
+        assert!(large.fee > high.fee && large.fee_rate() < middle.fee_rate());
+
+        for entry in [&low, &high, &middle, &large] {
+
+            assert!(mempool.insert(entry.clone()).is_empty());
+        }
+
+        let order: Vec<Hash> = mempool.iter().map(|entry| entry.txid()).collect();
+
+        assert_eq!(order, vec![high.txid(), middle.txid(), large.txid(), low.txid()]);
+    }
+
+
+    #[test]
+    fn evicts_the_lowest_fee_rates_once_full() {
+
+        let entries: Vec<MempoolEntry> = [5_000, 1_000, 20_000]
+            .into_iter()
+            .enumerate()
+            .map(|(n, fee)| entry(&[coin(n as u64)], 1, fee))
+            .collect();
+
+        let newcomer = entry(&[coin(3)], 1, 50_000);
+
+        // signatures differ in size by a byte or two, the mempool is made just large enough
+        // for the three entries, and for the newcomer in place of the one paying the least
+
+        let size: usize = entries.iter().map(|entry| entry.size).sum();
+
+        let mut mempool = Mempool::new(size - entries[1].size + entries[1].size.max(newcomer.size));
+
+        for entry in &entries {
+
+            assert!(mempool.insert(entry.clone()).is_empty());
+        }
+
+        assert_eq!(mempool.min_fee_rate(), FeeRate::ZERO);
+
+        // one more pushes out the one paying the least
+
+        let evicted = mempool.insert(newcomer.clone());
+
+        assert_eq!(txids(&evicted), HashSet::from([entries[1].txid()]));
+        assert!(mempool.contains(&newcomer.txid()));
+        assert!(mempool.size() <= mempool.max_size());
+
+        // and what was evicted can not come straight back
+
+        assert_eq!(mempool.min_fee_rate(), entries[1].fee_rate().saturating_add(INCREMENTAL_RELAY_FEE));
+
+        // a newcomer paying less than everything else is the one to go
+
+        let cheap = entry(&[coin(4)], 1, 2_000);
+
+        assert_eq!(txids(&mempool.insert(cheap.clone())), HashSet::from([cheap.txid()]));
+        assert_eq!(mempool.len(), 3);
+    }
+
+
+    #[test]
+    fn minimum_fee_rate_decays_after_an_eviction() {
+
+        let evicted = entry(&[coin(0)], 1, 100_000);
+        let newcomer = entry(&[coin(1)], 1, 200_000);
+
+        let mut mempool = Mempool::new(evicted.size.max(newcomer.size));
+
+        mempool.insert(evicted.clone());
+
+        assert_eq!(txids(&mempool.insert(newcomer)), HashSet::from([evicted.txid()]));
+
+        let minimum = evicted.fee_rate().saturating_add(INCREMENTAL_RELAY_FEE);
+        let evicted_at = mempool.last_eviction;
+
+        assert_eq!(mempool.min_fee_rate_at(evicted_at), minimum);
+        assert_eq!(mempool.min_fee_rate_at(evicted_at + MIN_FEE_HALFLIFE - Duration::seconds(1)), minimum);
+
+        // it halves once every half-life
+
+        assert_eq!(
+            mempool.min_fee_rate_at(evicted_at + MIN_FEE_HALFLIFE),
+            FeeRate::from_sat_per_kb(minimum.to_sat_per_kb() / 2),
+        );
+
+        assert_eq!(
+            mempool.min_fee_rate_at(evicted_at + MIN_FEE_HALFLIFE * 3),
+            FeeRate::from_sat_per_kb(minimum.to_sat_per_kb() / 8),
+        );
+
+        // until it is too small to matter
+
+        assert_eq!(mempool.min_fee_rate_at(evicted_at + MIN_FEE_HALFLIFE * 30), FeeRate::ZERO);
+    }
+
+
+    #[test]
+    fn limits_the_number_of_unconfirmed_ancestors() {
+
+        let mut mempool = Mempool::new(1_000_000);
+
+        let mut tip = coin(0);
+
+        // a chain of MAX_ANCESTORS transactions, each spending the one before
+
+        for _ in 0..MAX_ANCESTORS {
+
+            let entry = entry(&[tip], 1, 1_000);
+
+            mempool.check_chain_limits(&entry.transaction, entry.size, &HashSet::new()).unwrap();
+
+            tip = entry.transaction.outpoint(0);
+
+            mempool.insert(entry);
+        }
+
+        let entry = entry(&[tip], 1, 1_000);
+
+        let error = mempool.check_chain_limits(&entry.transaction, entry.size, &HashSet::new()).unwrap_err();
+
+        assert!(error.to_string().contains("too many unconfirmed ancestors"), "{}", error);
+    }
+
+
+    #[test]
+    fn limits_the_number_of_unconfirmed_descendants() {
+
+        let mut mempool = Mempool::new(1_000_000);
+
+        let parent = entry(&[coin(0)], MAX_DESCENDANTS + 1, 1_000);
+
+        mempool.insert(parent.clone());
+
+        // together with the parent, MAX_DESCENDANTS transactions
+
+        let children: Vec<MempoolEntry> = (0..MAX_DESCENDANTS - 1)
+            .map(|vout| entry(&[parent.transaction.outpoint(vout)], 1, 1_000))
+            .collect();
+
+        for child in &children {
+
+            mempool.check_chain_limits(&child.transaction, child.size, &HashSet::new()).unwrap();
+
+            mempool.insert(child.clone());
+        }
+
+        let extra = entry(&[parent.transaction.outpoint(MAX_DESCENDANTS)], 1, 1_000);
+
+        let error = mempool.check_chain_limits(&extra.transaction, extra.size, &HashSet::new()).unwrap_err();
+
+        assert!(error.to_string().contains("too many unconfirmed descendants"), "{}", error);
+
+        // a descendant that is about to be replaced makes room
+
+        let replaced = HashSet::from([children[0].txid()]);
+
+        mempool.check_chain_limits(&extra.transaction, extra.size, &replaced).unwrap();
+    }
+
+
+    #[test]
+    fn limits_the_size_of_unconfirmed_chains() {
+
+        let mut mempool = Mempool::new(1_000_000);
+
+        let parent = entry(&[coin(0)], 2, 1_000);
+        let child = entry(&[parent.transaction.outpoint(0)], 1, 1_000);
+
+        mempool.insert(parent.clone());
+        mempool.insert(child.clone());
+
+        let sibling = entry(&[parent.transaction.outpoint(1)], 1, 1_000).transaction;
+
+        // the size passed in stands for the new transaction's own
+
+        let error = mempool.check_chain_limits(&sibling, MAX_ANCESTOR_SIZE - parent.size + 1, &HashSet::new()).unwrap_err();
+
+        assert!(error.to_string().contains("unconfirmed ancestors are too large"), "{}", error);
+
+        // small enough next to its parent, but the parent with all its descendants would be too large
+
+        let size = MAX_DESCENDANT_SIZE - parent.size - child.size + 1;
+
+        assert!(parent.size + size <= MAX_ANCESTOR_SIZE);
+
+        let error = mempool.check_chain_limits(&sibling, size, &HashSet::new()).unwrap_err();
+
+        assert!(error.to_string().contains("unconfirmed descendants are too large"), "{}", error);
+
+        mempool.check_chain_limits(&sibling, size - 1, &HashSet::new()).unwrap();
+    }
+
+
+    #[test]
+    fn removes_descendants_along_with_a_transaction() {
+
+        let mut mempool = Mempool::new(1_000_000);
+
+        let parent = entry(&[coin(0)], 1, 1_000);
+        let child = entry(&[parent.transaction.outpoint(0)], 1, 1_000);
+        let grandchild = entry(&[child.transaction.outpoint(0)], 1, 1_000);
+        let unrelated = entry(&[coin(1)], 1, 1_000);
+
+        for entry in [&parent, &child, &grandchild, &unrelated] {
+
+            mempool.insert(entry.clone());
+        }
+
+        let removed = mempool.remove_with_descendants(&parent.txid());
+
+        assert_eq!(txids(&removed), HashSet::from([parent.txid(), child.txid(), grandchild.txid()]));
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&unrelated.txid()));
+        assert_eq!(mempool.size(), unrelated.size);
+        assert_eq!(mempool.spender(&child.transaction.inputs[0].previous_output), None);
+    }
+
+
+    #[test]
+    fn evicts_descendants_along_with_a_cheap_parent() {
+
+        let parent = entry(&[coin(0)], 1, 1_000);
+        let child = entry(&[parent.transaction.outpoint(0)], 1, 500_000);
+        let other = entry(&[coin(1)], 1, 100_000);
+
+        // one byte short of holding all three
+
+        let mut mempool = Mempool::new(parent.size + child.size + other.size - 1);
+
+        mempool.insert(parent.clone());
+        mempool.insert(child.clone());
+
+        // the child pays the most, but it can not stay without its parent
+
+        let evicted = mempool.insert(other.clone());
+
+        assert_eq!(txids(&evicted), HashSet::from([parent.txid(), child.txid()]));
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&other.txid()));
+    }
+
+
+    #[test]
+    fn keeps_the_children_of_a_mined_transaction() {
+
+        let mut mempool = Mempool::new(1_000_000);
+
+        let parent = entry(&[coin(0)], 1, 1_000);
+        let child = entry(&[parent.transaction.outpoint(0)], 1, 1_000);
+
+        mempool.insert(parent.clone());
+        mempool.insert(child.clone());
+
+        mempool.remove(&parent.txid());
+
+        // the child now spends a confirmed output
+
+        assert!(mempool.contains(&child.txid()));
+        assert_eq!(mempool.parents(&child.txid()).count(), 0);
+        assert!(mempool.ancestors(&child.txid()).is_empty());
+    }
+
+
+    #[test]
+    fn rejects_a_replacement_spending_what_it_replaces() {
+
+        let mut mempool = Mempool::new(1_000_000);
+
+        let original = entry(&[coin(0)], 2, 1_000);
+
+        mempool.insert(original.clone());
+
+        let replacement = entry(&[coin(0), original.transaction.outpoint(1)], 1, 100_000);
+
+        let conflicts = mempool.conflicts(&replacement.transaction);
+
+        assert_eq!(conflicts, HashSet::from([original.txid()]));
+
+        assert_eq!(
+            mempool.check_replacement(&replacement, &conflicts),
+            Err(ReplacementRejection::SpendsConflictingTransaction),
+        );
+    }
+
+
+    #[test]
+    fn rejects_a_replacement_evicting_too_many_transactions() {
+
+        let mut mempool = Mempool::new(1_000_000);
+
+        // the original and its children, as many as a replacement may evict
+
+        let original = entry(&[coin(0)], MAX_REPLACEMENTS + 1, 1_000);
+
+        mempool.insert(original.clone());
+
+        for vout in 0..MAX_REPLACEMENTS - 1 {
+
+            mempool.insert(entry(&[original.transaction.outpoint(vout)], 1, 1_000));
+        }
+
+        let replacement = entry(&[coin(0)], 1, 1_000_000);
+
+        let conflicts = mempool.conflicts(&replacement.transaction);
+
+        assert_eq!(conflicts.len(), MAX_REPLACEMENTS);
+        assert_eq!(mempool.check_replacement(&replacement, &conflicts), Ok(()));
+
+        // one more is too many
+
+        mempool.insert(entry(&[original.transaction.outpoint(MAX_REPLACEMENTS)], 1, 1_000));
+
+        let conflicts = mempool.conflicts(&replacement.transaction);
+
+        assert_eq!(
+            mempool.check_replacement(&replacement, &conflicts),
+            Err(ReplacementRejection::TooManyReplacements { count: MAX_REPLACEMENTS + 1, max: MAX_REPLACEMENTS }),
+        );
+    }
+
+
+    #[test]
+    fn rejects_a_replacement_without_a_higher_fee_rate() {
+
+        let mut mempool = Mempool::new(1_000_000);
+
+        let original = entry(&[coin(0)], 1, 10_000);
+
+        mempool.insert(original.clone());
+
+        // the same fee in a larger transaction
+
+        let replacement = entry(&[coin(0)], 2, 10_000);
+
+        let conflicts = mempool.conflicts(&replacement.transaction);
+
+        assert_eq!(
+            mempool.check_replacement(&replacement, &conflicts),
+            Err(ReplacementRejection::FeeRateTooLow {
+                fee_rate: replacement.fee_rate(),
+                conflicting: original.fee_rate(),
+            }),
+        );
+    }
+
+
+    #[test]
+    fn rejects_a_replacement_without_enough_fee() {
+
+        let mut mempool = Mempool::new(1_000_000);
+
+        // a large transaction with a low fee rate but a high fee
+
+        let original = entry(&[coin(0)], 40, 20_000);
+
+        mempool.insert(original.clone());
+
+        let transaction = entry(&[coin(0)], 1, 0).transaction;
+
+        let required = Amount::from_sat(20_000)
+            .checked_add(INCREMENTAL_RELAY_FEE.fee(transaction.serialized_size()))
+            .unwrap();
+
+        // a better fee rate, but it has to pay for what it evicts as well as its own relay
+
+        let replacement = MempoolEntry::new(transaction.clone(), Amount::from_sat(required.to_sat() - 1), Utc::now());
+
+        assert!(replacement.fee_rate() > original.fee_rate());
+
+        let conflicts = mempool.conflicts(&replacement.transaction);
+
+        assert_eq!(
+            mempool.check_replacement(&replacement, &conflicts),
+            Err(ReplacementRejection::FeeTooLow { fee: replacement.fee, required }),
+        );
+
+        let replacement = MempoolEntry::new(transaction, required, Utc::now());
+
+        assert_eq!(mempool.check_replacement(&replacement, &conflicts), Ok(()));
+    }
+}
@@ -1,9 +1,19 @@
 
 use serde::{Deserialize, Serialize};
 use crate::crypto::PublicKey;
-use crate::types::{Block, Transaction, TransactionOutput};
-use std::io::{Error as IoError, Read, Write};
+use crate::mempool::TransactionRejection;
+use crate::sha256::Hash;
+use crate::types::{Block, OutPoint, Transaction, TransactionOutput};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::validation::MAX_BLOCK_SIZE;
+use crate::U256;
+
+
+// the largest message we accept, anything longer is refused before we allocate room for it
+// a block is at most MAX_BLOCK_SIZE, the rest leaves room for long lists of UTXOs
+
+pub const MAX_MESSAGE_SIZE: usize = 4 * MAX_BLOCK_SIZE;
 
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -12,12 +22,16 @@ pub enum Message {
     // fetch all UTXOs, belonging to a public key 
     FetchUTXOS(PublicKey),
 
-    // UTXOS belonging to public key , bool determines if marked
-    UTXOS(Vec<(TransactionOutput, bool)>),
+    // UTXOS belonging to public key, with the outpoint to spend them by, bool determines if marked
+    UTXOS(Vec<(OutPoint, TransactionOutput, bool)>),
 
     // Send a transaction to the network
     SubmitTransaction(Transaction),
 
+    // The answer to SubmitTransaction: whether the node took the transaction with this id into its mempool,
+    // and if not, why
+    TransactionStatus(Hash, Result<(), TransactionRejection>),
+
     // Broadcast a new transaction to other nodes
     NewTransaction(Transaction),
 
@@ -52,6 +66,12 @@ pub enum Message {
     // This is the response to AskDifference
     Difference(i32),
 
+    // Ask a node how much work its chain has
+    AskChainWork,
+
+    // This is the response to AskChainWork: the total work of the node's active chain, and its height
+    ChainWork(U256, u64),
+
     // Ask a node to send a block with the specified height
     FetchBlock(usize),
 
@@ -63,6 +83,7 @@ pub enum Message {
 
 
 // We are going to use length-prefixed encoding for message and we are going to use ciborium for serialization
+// every message starts with the magic bytes of the network it belongs to (see ChainParams)
 
 impl Message {
 
@@ -82,12 +103,14 @@ impl Message {
 
     }
 
-    pub async fn send_async(&self, stream: &mut( impl AsyncWrite + Unpin)) -> Result<(), ciborium::ser::Error<IoError>> {
+    pub async fn send_async(&self, magic: [u8; 4], stream: &mut( impl AsyncWrite + Unpin)) -> Result<(), ciborium::ser::Error<IoError>> {
 
         let bytes = self.encode()?;
 
         let len = bytes.len() as u64;
 
+        stream.write_all(&magic).await?;
+
         stream.write_all(&len.to_be_bytes()).await?;
 
         stream.write_all(&bytes).await?;
@@ -96,16 +119,29 @@ impl Message {
     }
 
 
-    pub async  fn recieve_asynce(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, ciborium::de::Error<IoError>> {
+    pub async  fn recieve_asynce(magic: [u8; 4], stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, ciborium::de::Error<IoError>> {
+
+        let mut magic_bytes = [0u8; 4];
+
+        stream.read_exact(&mut magic_bytes).await?;
 
+        if magic_bytes != magic {
+
+            return Err(IoError::new(IoErrorKind::InvalidData, "message from another network").into());
+        }
 
         let mut len_bytes= [0u8; 8];
 
         stream.read_exact(&mut len_bytes).await?;
 
-        let len = u64::from_be_bytes(len_bytes) as usize;
+        let len = u64::from_be_bytes(len_bytes);
+
+        if len > MAX_MESSAGE_SIZE as u64 {
+
+            return Err(IoError::new(IoErrorKind::InvalidData, "message is too large").into());
+        }
 
-        let mut data = vec![0u8; len];
+        let mut data = vec![0u8; len as usize];
 
         stream.read_exact(&mut data).await?;
 
@@ -0,0 +1,251 @@
+// Chain parameters: everything that makes one chain different from another.
+// Every network (mainnet, testnet, regtest) has its own genesis block, its own network magic
+// and its own consensus constants, so the same binaries can run any of them side by side.
+
+use chrono::DateTime;
+
+use crate::amount::Amount;
+use crate::crypto::PublicKey;
+use crate::sha256::Hash;
+use crate::types::{Block, BlockHeader, Transaction, TransactionOutput};
+use crate::util::MerkleRoot;
+use crate::U256;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+
+// the genesis block pays its reward to this key, nobody holds the private key for it
+// (it is the key the first bitcoin block paid to)
+
+const GENESIS_PUBLIC_KEY: &str = "04678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f";
+
+// the extra nonce of the genesis coinbase, again borrowed from the first bitcoin block
+
+const GENESIS_MESSAGE: &[u8] = b"The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+
+    Mainnet,
+
+    Testnet,
+
+    Regtest,
+}
+
+impl fmt::Display for Network {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+        match self {
+
+            Network::Mainnet => write!(f, "mainnet"),
+
+            Network::Testnet => write!(f, "testnet"),
+
+            Network::Regtest => write!(f, "regtest"),
+        }
+    }
+}
+
+impl FromStr for Network {
+
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+
+        match s {
+
+            "mainnet" => Ok(Network::Mainnet),
+
+            "testnet" => Ok(Network::Testnet),
+
+            "regtest" => Ok(Network::Regtest),
+
+            _ => Err(format!("unknown network {}, expected mainnet, testnet or regtest", s)),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+
+    pub network: Network,
+
+    // the first bytes of every message on this network, so nodes of different networks
+    // can not accidentally talk to each other
+    pub magic: [u8; 4],
+
+    pub default_port: u16,
+
+    // reward of the first blocks, it halves every halving_interval blocks
+    pub initial_reward: Amount,
+
+    // halving interval in blocks
+    pub halving_interval: u64,
+
+    // ideal block time in seconds
+    pub ideal_block_time: u64,
+
+    // the easiest target a block can have, it has to be exactly representable in compact form
+    pub min_target: U256,
+
+    // difficulty update interval in blocks
+    pub difficulty_update_interval: u64,
+
+    // whether the target is adjusted at all, regtest keeps it at min_target
+    pub adjust_difficulty: bool,
+
+    // how many blocks have to be built on top of a coinbase before its outputs can be spent
+    pub coinbase_maturity: u64,
+
+    // maximum mempool transaction age in seconds
+    pub max_mempool_transaction_age: u64,
+
+    // how many bytes of transactions the mempool may hold, the lowest fee rates are evicted beyond that
+    pub max_mempool_size: usize,
+
+    // how far ahead of our clock a block's timestamp may be, in seconds
+    pub max_future_block_time: u64,
+
+    // the genesis block is fully determined by its timestamp and nonce
+    pub genesis_timestamp: i64,
+    pub genesis_nonce: u64,
+}
+
+impl ChainParams {
+
+    pub fn mainnet() -> Self {
+
+        ChainParams {
+            network: Network::Mainnet,
+            magic: [0xf9, 0xbe, 0xb4, 0xd9],
+            default_port: 9000,
+            initial_reward: Amount::from_btc(50),
+            halving_interval: 210,
+            ideal_block_time: 10,
+            // 0x1f00ffff in compact form
+            min_target: U256([0, 0, 0, 0x0000_FFFF_0000_0000]),
+            difficulty_update_interval: 50,
+            adjust_difficulty: true,
+            coinbase_maturity: 100,
+            max_mempool_transaction_age: 600,
+            max_mempool_size: 300_000_000,
+            max_future_block_time: 2 * 60 * 60,
+            genesis_timestamp: 1_727_740_800,
+            genesis_nonce: 98_216,
+        }
+    }
+
+
+    // same rules as mainnet, but a separate chain to try things out on
+
+    pub fn testnet() -> Self {
+
+        ChainParams {
+            network: Network::Testnet,
+            magic: [0x0b, 0x11, 0x09, 0x07],
+            default_port: 19000,
+            genesis_timestamp: 1_727_827_200,
+            genesis_nonce: 90_232,
+            ..Self::mainnet()
+        }
+    }
+
+
+    // a local chain for testing, blocks are trivial to mine and the difficulty never changes
+
+    pub fn regtest() -> Self {
+
+        ChainParams {
+            network: Network::Regtest,
+            magic: [0xfa, 0xbf, 0xb5, 0xda],
+            default_port: 29000,
+            halving_interval: 150,
+            // 0x207fffff in compact form, half of all hashes meet it
+            min_target: U256([0, 0, 0, 0x7FFF_FF00_0000_0000]),
+            adjust_difficulty: false,
+            genesis_timestamp: 1_727_913_600,
+            genesis_nonce: 3,
+            ..Self::mainnet()
+        }
+    }
+
+
+    pub fn for_network(network: Network) -> Self {
+
+        match network {
+
+            Network::Mainnet => Self::mainnet(),
+
+            Network::Testnet => Self::testnet(),
+
+            Network::Regtest => Self::regtest(),
+        }
+    }
+
+
+    // the first block of the chain, every node of the network starts out with it
+
+    pub fn genesis_block(&self) -> Block {
+
+        let public_key = PublicKey::from_sec1_bytes(
+            &hex::decode(GENESIS_PUBLIC_KEY).expect("BUG: invalid genesis public key hex"),
+        )
+        .expect("BUG: invalid genesis public key");
+
+        let transactions = vec![Transaction::new_coinbase(
+            0,
+            GENESIS_MESSAGE.to_vec(),
+            vec![TransactionOutput {
+                value: self.initial_reward,
+                pubkey: public_key,
+            }],
+        )];
+
+        let header = BlockHeader::new(
+            DateTime::from_timestamp(self.genesis_timestamp, 0).expect("BUG: invalid genesis timestamp"),
+            self.genesis_nonce,
+            Hash::zero(),
+            MerkleRoot::calculate(&transactions),
+            self.min_target.to_compact(),
+        );
+
+        Block::new(header, transactions)
+    }
+
+
+    pub fn genesis_hash(&self) -> Hash {
+
+        self.genesis_block().hash()
+    }
+
+
+    // block reward for the block at `height`, nothing is left after 64 halvings
+
+    pub fn block_reward(&self, height: u64) -> Amount {
+
+        u32::try_from(height / self.halving_interval)
+            .ok()
+            .and_then(|halvings| self.initial_reward.checked_shr(halvings))
+            .unwrap_or(Amount::ZERO)
+    }
+
+
+    // where this network keeps its data under `base`, mainnet uses `base` itself
+    // and the other networks a subdirectory named after them
+
+    pub fn data_dir<P: AsRef<Path>>(&self, base: P) -> PathBuf {
+
+        match self.network {
+
+            Network::Mainnet => base.as_ref().to_path_buf(),
+
+            network => base.as_ref().join(network.to_string()),
+        }
+    }
+}
@@ -6,7 +6,7 @@ use serde::{Deserialize, Serialize};
 use sha256::digest;
 
 
-#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq,Eq, Hash)]
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq,Eq, PartialOrd, Ord, Hash)]
 pub struct Hash(U256);
 
 impl Hash {
@@ -66,6 +66,20 @@ impl Hash {
         
         bytes.as_slice().try_into().unwrap()
     }
+
+
+    // the inverse of as_bytes
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+
+        let mut words = [0u64; 4];
+
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = u64::from_le_bytes(bytes[i * 8..(i + 1) * 8].try_into().unwrap());
+        }
+
+        Hash(U256(words))
+    }
  
 
 }
@@ -0,0 +1,338 @@
+// Block storage: instead of serializing every block in one big blob, blocks are appended
+// to numbered block files (blk00000.dat, blk00001.dat, ...) and never rewritten.
+// An append-only index file maps each block hash to the file and offset its block lives at,
+// so a single block can be read back without loading anything else.
+//
+// The undo data of connected blocks is stored the same way, as its own kind of record.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::sha256::Hash;
+use crate::types::Block;
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{
+    BufReader, Error as IoError, ErrorKind as IoErrorKind, Read,
+    Result as IoResult, Seek, SeekFrom, Write,
+};
+use std::path::{Path, PathBuf};
+
+
+// once a block file grows past this size, we start a new one
+
+const MAX_BLOCK_FILE_SIZE: u64 = 128 * 1024 * 1024;
+
+const INDEX_FILE: &str = "index.dat";
+
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+enum RecordKind {
+
+    Block,
+
+    Undo,
+}
+
+
+// where a record lives: the number of the block file, and the offset and length of its data
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct Location {
+
+    file: u32,
+    offset: u64,
+    len: u64,
+}
+
+
+// one entry of the index file
+
+#[derive(Serialize, Deserialize, Debug)]
+struct IndexEntry {
+
+    kind: RecordKind,
+    hash: Hash,
+    location: Location,
+}
+
+
+#[derive(Debug)]
+pub struct BlockStore {
+
+    dir: PathBuf,
+
+    blocks: HashMap<Hash, Location>,
+    undo: HashMap<Hash, Location>,
+
+    // the block file we are currently appending to, and how large it is
+    current_file_number: u32,
+    current_file: File,
+    current_file_size: u64,
+
+    index_file: File,
+}
+
+impl BlockStore {
+
+    // open the block store in `dir`, creating it if it does not exist yet
+
+    pub fn open<P: AsRef<Path>>(dir: P) -> IoResult<Self> {
+
+        let dir = dir.as_ref().to_path_buf();
+
+        fs::create_dir_all(&dir)?;
+
+        let mut blocks = HashMap::new();
+        let mut undo = HashMap::new();
+
+        let index_path = dir.join(INDEX_FILE);
+
+        let mut index_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&index_path)?;
+
+        // replay the index, a crash in the middle of writing an entry leaves
+        // an incomplete one at the end, which we cut off
+
+        let mut valid_len = 0;
+
+        {
+            let mut reader = BufReader::new(&mut index_file);
+
+            while let Some((entry, len)) = read_record::<IndexEntry, _>(&mut reader)? {
+
+                match entry.kind {
+
+                    RecordKind::Block => blocks.insert(entry.hash, entry.location),
+
+                    RecordKind::Undo => undo.insert(entry.hash, entry.location),
+                };
+
+                valid_len += len;
+            }
+        }
+
+        index_file.set_len(valid_len)?;
+        index_file.seek(SeekFrom::End(0))?;
+
+        // continue appending to the last block file
+
+        let mut current_file_number = 0;
+
+        while block_file_path(&dir, current_file_number + 1).exists() {
+
+            current_file_number += 1;
+        }
+
+        let current_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(block_file_path(&dir, current_file_number))?;
+
+        let current_file_size = current_file.metadata()?.len();
+
+        Ok(BlockStore {
+            dir,
+            blocks,
+            undo,
+            current_file_number,
+            current_file,
+            current_file_size,
+            index_file,
+        })
+    }
+
+
+    pub fn contains_block(&self, hash: &Hash) -> bool {
+
+        self.blocks.contains_key(hash)
+    }
+
+
+    // append a block, storing the same block twice is a no-op
+
+    pub fn put_block(&mut self, block: &Block) -> IoResult<()> {
+
+        let hash = block.hash();
+
+        if self.contains_block(&hash) {
+
+            return Ok(());
+        }
+
+        self.append(RecordKind::Block, hash, block)
+    }
+
+
+    pub fn get_block(&self, hash: &Hash) -> IoResult<Option<Block>> {
+
+        match self.blocks.get(hash) {
+
+            Some(location) => self.read(location).map(Some),
+
+            None => Ok(None),
+        }
+    }
+
+
+    // append the undo data of the block with the given hash, replacing any earlier one
+
+    pub fn put_undo<T: Serialize>(&mut self, hash: Hash, undo: &T) -> IoResult<()> {
+
+        self.append(RecordKind::Undo, hash, undo)
+    }
+
+
+    pub fn get_undo<T: DeserializeOwned>(&self, hash: &Hash) -> IoResult<Option<T>> {
+
+        match self.undo.get(hash) {
+
+            Some(location) => self.read(location).map(Some),
+
+            None => Ok(None),
+        }
+    }
+
+
+    // make sure everything written so far is on disk
+
+    pub fn sync(&self) -> IoResult<()> {
+
+        self.current_file.sync_all()?;
+
+        self.index_file.sync_all()
+    }
+
+
+    fn append<T: Serialize>(&mut self, kind: RecordKind, hash: Hash, value: &T) -> IoResult<()> {
+
+        if self.current_file_size >= MAX_BLOCK_FILE_SIZE {
+
+            // the previous file is complete, it will never be written to again
+
+            self.current_file.sync_all()?;
+
+            self.current_file_number += 1;
+
+            self.current_file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(block_file_path(&self.dir, self.current_file_number))?;
+
+            self.current_file_size = 0;
+        }
+
+        let data = encode(value)?;
+
+        let location = Location {
+            file: self.current_file_number,
+            offset: self.current_file_size + 8,
+            len: data.len() as u64,
+        };
+
+        write_record(&mut self.current_file, &data)?;
+
+        self.current_file_size += 8 + data.len() as u64;
+
+        // the index entry is written only after the record itself,
+        // so the index never points at data that is not there
+
+        write_record(&mut self.index_file, &encode(&IndexEntry { kind, hash, location })?)?;
+
+        match kind {
+
+            RecordKind::Block => self.blocks.insert(hash, location),
+
+            RecordKind::Undo => self.undo.insert(hash, location),
+        };
+
+        Ok(())
+    }
+
+
+    fn read<T: DeserializeOwned>(&self, location: &Location) -> IoResult<T> {
+
+        let mut file = File::open(block_file_path(&self.dir, location.file))?;
+
+        file.seek(SeekFrom::Start(location.offset))?;
+
+        let mut data = vec![0u8; location.len as usize];
+
+        file.read_exact(&mut data)?;
+
+        ciborium::de::from_reader(data.as_slice()).map_err(|_| {
+
+            IoError::new(IoErrorKind::InvalidData, "failed to deserialize stored record")
+        })
+    }
+}
+
+
+fn block_file_path(dir: &Path, number: u32) -> PathBuf {
+
+    dir.join(format!("blk{:05}.dat", number))
+}
+
+
+fn encode<T: Serialize>(value: &T) -> IoResult<Vec<u8>> {
+
+    let mut data = vec![];
+
+    ciborium::ser::into_writer(value, &mut data).map_err(|_| {
+
+        IoError::new(IoErrorKind::InvalidData, "failed to serialize record")
+    })?;
+
+    Ok(data)
+}
+
+
+// records are length-prefixed, just like network messages
+
+fn write_record<O: Write>(writer: &mut O, data: &[u8]) -> IoResult<()> {
+
+    writer.write_all(&(data.len() as u64).to_be_bytes())?;
+
+    writer.write_all(data)
+}
+
+
+// read the next record, returns None at the end of the data or if the last record is incomplete
+// together with the record we return how many bytes it took up
+
+fn read_record<T: DeserializeOwned, I: Read>(reader: &mut I) -> IoResult<Option<(T, u64)>> {
+
+    let mut len_bytes = [0u8; 8];
+
+    if let Err(e) = reader.read_exact(&mut len_bytes) {
+
+        return match e.kind() {
+
+            IoErrorKind::UnexpectedEof => Ok(None),
+
+            _ => Err(e),
+        };
+    }
+
+    let len = u64::from_be_bytes(len_bytes);
+
+    let mut data = vec![];
+
+    reader.take(len).read_to_end(&mut data)?;
+
+    if (data.len() as u64) < len {
+
+        return Ok(None);
+    }
+
+    match ciborium::de::from_reader(data.as_slice()) {
+
+        Ok(value) => Ok(Some((value, 8 + len))),
+
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,178 @@
+// Block templates: the block a miner works on next.
+// A template is a coinbase paying the miner followed by mempool transactions, the ones with the
+// highest fee rates first, as long as the block stays within its limits. A transaction only goes in
+// together with, and after, the mempool transactions it spends from. The header is complete except
+// for the nonce, so once mined the template is a valid block on top of the current tip.
+
+use chrono::Utc;
+
+use crate::amount::{Amount, FeeRate, MAX_MONEY};
+use crate::crypto::PublicKey;
+use crate::error::Result;
+use crate::mempool::MempoolEntry;
+use crate::types::{Block, BlockHeader, Blockchain, Transaction, TransactionOutput};
+use crate::util::MerkleRoot;
+use crate::validation::{self, Rule, RuleViolation, MAX_BLOCK_SIGNATURE_CHECKS, MAX_BLOCK_SIZE};
+
+use std::collections::HashSet;
+
+
+// bytes kept free in a template, the encoding of the nonce, the timestamp and the coinbase value
+// can grow a little when the block is mined and the fees are filled in
+
+pub const TEMPLATE_SIZE_RESERVE: usize = 64;
+
+
+// how much a template may contain, anything above the consensus limits is capped to them
+
+#[derive(Clone, Copy, Debug)]
+pub struct TemplateLimits {
+
+    // serialized size of the whole block, coinbase included
+    pub max_size: usize,
+
+    // signature checks of the whole block, one per input
+    pub max_signature_checks: usize,
+
+    // transactions besides the coinbase, consensus has no such limit so there is none by default
+    pub max_transactions: Option<usize>,
+}
+
+impl Default for TemplateLimits {
+
+    fn default() -> Self {
+
+        TemplateLimits {
+            max_size: MAX_BLOCK_SIZE,
+            max_signature_checks: MAX_BLOCK_SIGNATURE_CHECKS,
+            max_transactions: None,
+        }
+    }
+}
+
+
+impl Blockchain {
+
+    // the block to mine next on top of the tip, its coinbase pays the block reward plus all fees to `payout`
+
+    pub fn build_template(&self, payout: PublicKey, limits: TemplateLimits) -> Result<Block> {
+
+        let max_size = limits.max_size.min(MAX_BLOCK_SIZE);
+        let max_signature_checks = limits.max_signature_checks.min(MAX_BLOCK_SIGNATURE_CHECKS);
+
+        let coinbase = Transaction::new_coinbase(
+            self.blocks_height(),
+            vec![],
+            vec![TransactionOutput {
+                value: Amount::ZERO,
+                pubkey: payout,
+            }],
+        );
+
+        // a block's timestamp has to be after the median of the ones before it, even if our clock is behind
+
+        let timestamp = match validation::median_time_past(self.headers()) {
+
+            Some(median) if median >= Utc::now() => median + chrono::Duration::seconds(1),
+
+            _ => Utc::now(),
+        };
+
+        let transactions = vec![coinbase];
+
+        let mut block = Block::new(
+            BlockHeader::new(timestamp, 0, self.tip_hash(), MerkleRoot::calculate(&transactions), self.target().to_compact()),
+            transactions,
+        );
+
+        // transactions are ranked by the fee rate of their package, the transaction together with
+        // its ancestors that are still in the mempool, as those have to go into the block with it
+        // a child paying a high fee this way also gets its parents in
+
+        let mempool = self.mempool();
+
+        let mut candidates: Vec<(FeeRate, &MempoolEntry)> = mempool
+            .iter()
+            .map(|entry| {
+
+                let ancestors = mempool.ancestors(&entry.txid());
+
+                let package = ancestors.iter().filter_map(|txid| mempool.get(txid)).chain([entry]);
+
+                let (fee, size) = package.fold((Amount::ZERO, 0), |(fee, size), entry| {
+
+                    (fee.checked_add(entry.fee).unwrap_or(MAX_MONEY), size + entry.size)
+                });
+
+                (FeeRate::new(fee, size), entry)
+            })
+            .collect();
+
+        // a stable sort, equal packages stay ordered by their own fee rate
+
+        candidates.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+        // pick packages until the block is full, a package that does not fit can leave room for a smaller one
+
+        let mut size = block.serialized_size() + TEMPLATE_SIZE_RESERVE;
+        let mut signature_checks = 0;
+        let mut fees = vec![];
+
+        let mut included = HashSet::new();
+
+        for (_, entry) in candidates {
+
+            let txid = entry.txid();
+
+            if included.contains(&txid) {
+
+                continue;
+            }
+
+            // parents before their children
+
+            let mut package: Vec<&MempoolEntry> = mempool
+                .ancestors(&txid)
+                .iter()
+                .filter(|ancestor| !included.contains(*ancestor))
+                .filter_map(|ancestor| mempool.get(ancestor))
+                .chain([entry])
+                .collect();
+
+            package.sort_by_key(|entry| mempool.ancestors(&entry.txid()).len());
+
+            let package_size: usize = package.iter().map(|entry| entry.size).sum();
+            let package_signature_checks: usize = package.iter().map(|entry| entry.transaction.inputs.len()).sum();
+
+            if limits.max_transactions.is_some_and(|max| included.len() + package.len() > max)
+                || size + package_size > max_size
+                || signature_checks + package_signature_checks > max_signature_checks
+            {
+                continue;
+            }
+
+            size += package_size;
+            signature_checks += package_signature_checks;
+
+            for entry in package {
+
+                fees.push(entry.fee);
+                included.insert(entry.txid());
+
+                block.transactions.push(entry.transaction.clone());
+            }
+        }
+
+        // the coinbase collects the block reward plus every fee in the block
+
+        let value = Amount::checked_sum(fees)
+            .and_then(|fees| self.calculate_block_reward().checked_add(fees))
+            .ok_or(RuleViolation::block(Rule::Amounts, "fees overflow"))?;
+
+        block.transactions[0].outputs[0].value = value;
+
+        block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
+
+        Ok(block)
+    }
+}
@@ -9,5 +9,5 @@ mod transaction;
 pub use block::{Block, BlockHeader};
 pub use blockchain::Blockchain;
 pub use transaction::{
-Transaction, TransactionInput, TransactionOutput,
+signature_hash, CoinbaseInput, OutPoint, SigHashMode, SigHashType, Transaction, TransactionInput, TransactionOutput,
 };
\ No newline at end of file
@@ -1,14 +1,16 @@
-use chrono::naive::serde::ts_microseconds::deserialize;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use super::{Transaction, TransactionOutput};
-use crate::error::{BtcError, Result};
+use super::Transaction;
+use crate::amount::Amount;
+use crate::error::Result;
+use crate::params::ChainParams;
 use crate::sha256::Hash;
 use crate::util::MerkleRoot;
+use crate::utxo::{UtxoOverlay, UtxoSet};
+use crate::validation::{self, Rule, RuleViolation, ValidationLevel};
 use crate::U256;
-use std::collections::HashMap;
 
-use crate::util::Saveable;
+use crate::util::{ByteCounter, Saveable};
 use std::io::{
 Error as IoError, ErrorKind as IoErrorKind, Read,
 Result as IoResult, Write,
@@ -45,177 +47,55 @@ impl Block {
     }
 
 
-    // verify the all transaction in the block 
+    // the size of the block in bytes, the way it is stored and sent to other nodes
 
-    pub fn verify_transaction(&self, predicted_block_height: u64, utxos: &HashMap<Hash, (bool, TransactionOutput)>) -> Result<()> {
+    pub fn serialized_size(&self) -> usize {
 
-        let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+        let mut counter = ByteCounter::default();
 
-        // reject completely emtpy blocks
+        self.save(&mut counter).expect("BUG: a block can always be serialized");
 
-        if self.transactions.is_empty() {
-
-            return Err(BtcError::InvalidTransaction);
-        }
-
-        self.verify_coinbase_transaction(predicted_block_height, utxos)?;
-
-        for transaction in self.transactions.iter().skip(1)  {   // skipping the coinbase transaction 
-
-            let mut input_value = 0;
-            let mut output_value = 0;
-             
-            for input in &transaction.inputs {
-
-                let prev_output = utxos.get(&input.prev_transaction_output_hash).map(|(_, output)| output);
-                
-                if prev_output.is_none() {
-                    
-                    return Err(BtcError::InvalidTransaction);
-                }
-
-                let prev_output = prev_output.unwrap();
-
-                // prevent same-block double spending
-
-                if inputs.contains_key(&input.prev_transaction_output_hash) {
-
-                    return Err(BtcError::InvalidTransaction);
-                }
-
-                // check if the signature is valid
-
-                if !input.signature.verify(&input.prev_transaction_output_hash, &prev_output.pubkey) {
-
-                    return Err(BtcError::InvalidSignature);
-                }
-
-                input_value += prev_output.value;
-
-                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
-
-
-            }
-
-            for output in &transaction.outputs {
-
-                output_value += output.value;
-
-            }
-
-            // it is fine for output value to be less than input value as 
-            // the difference between them will be equal to fee for the miner
-
-            if input_value < output_value {
-
-                return Err(BtcError::InvalidTransaction);
-
-            }
-
-
-
-        }
-
-        Ok(())
+        counter.0
     }
 
-    
-    // verify coinbase transaction 
 
+    // verify the all transaction in the block
+    // this runs the transaction rules of validation.rs, the header is checked separately
 
-    pub fn verify_coinbase_transaction(&self, predicted_block_height: u64, utxos: &HashMap<Hash, (bool, TransactionOutput)>) -> Result<()> {
+    pub fn verify_transaction(&self, predicted_block_height: u64, utxos: &UtxoSet, params: &ChainParams) -> Result<()> {
 
+        validation::check_coinbase(self, predicted_block_height)?;
 
-        // coinbase tx is the first transaction in the block
+        validation::check_outputs(self)?;
 
-        let coinbase_transaction = &self.transactions[0];
+        validation::check_double_spends(self)?;
 
-        if coinbase_transaction.inputs.len() == 0 {
+        // every other transaction goes through the same checks as a mempool transaction, minus policy
+        // it can spend the outputs of the transactions before it in the block
 
-            return Err(BtcError::InvalidTransaction);
-        }
+        let mut view = UtxoOverlay::new(utxos);
 
-        if coinbase_transaction.outputs.len() == 0 {
+        view.add_transaction(&self.transactions[0], predicted_block_height);
 
-            return Err(BtcError::InvalidTransaction);
-        }
+        let mut miner_fees = Amount::ZERO;
 
+        for (idx, transaction) in self.transactions.iter().enumerate().skip(1) {
 
-        let miner_fees = self.calculate_miner_fees(utxos)?;
-        
-        let block_reward = crate::INITIAL_REWARD * 10u64.pow(8) / 2u64.pow((predicted_block_height / crate::HALVING_INTERVAL) as u32);
+            let fee = transaction
+                .validate(&view, predicted_block_height, params, ValidationLevel::Consensus)
+                .map_err(|e| e.in_transaction(idx))?;
 
-        let total_coinbase_outputs: u64 = coinbase_transaction.outputs.iter().map(|output| output.value).sum();
+            view.add_transaction(transaction, predicted_block_height);
 
-        if total_coinbase_outputs != block_reward + miner_fees {
-
-            return Err(BtcError::InvalidTransaction);
+            miner_fees = miner_fees
+                .checked_add(fee)
+                .ok_or(RuleViolation::transaction(Rule::Amounts, idx, "fees overflow"))?;
         }
 
-        Ok(())
-    }
-
-
-    pub fn calculate_miner_fees(&self, utxos: &HashMap<Hash, (bool, TransactionOutput)>) -> Result<u64> {
-         
-        let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
-        let mut outputs: HashMap<Hash, TransactionOutput> = HashMap::new();
-
-        // checks every transaction after coinbase
-
-        for transaction in self.transactions.iter().skip(1) {
-
-            for input in &transaction.inputs {
-
-                // input dont contain the values of the outputs, so we need to match the inputs to output
-                
-                let prev_output = utxos.get(
-                    &input.prev_transaction_output_hash,
-                ).map(|(_, output)| output);
-
-                if prev_output.is_none() {
-
-                    return Err(BtcError::InvalidTransaction);
-                }
-
-                let prev_output = prev_output.unwrap();
-
-                if inputs.contains_key(&input.prev_transaction_output_hash,) {
-
-                    return Err(BtcError::InvalidTransaction);
-
-
-                }
-
-                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
-
-            }
-
-            for output in &transaction.outputs {
-
-                if outputs.contains_key(&output.hash()) {
-
-                    return Err(BtcError::InvalidTransaction);
-                }
-
-                outputs.insert( output.hash(),output.clone(), );
-            }
-        }
-
-
-        let input_value:u64 = inputs.values().map(|output| output.value).sum();
-
-        let output_value:u64 = outputs.values().map(|output| output.value).sum();
-        
-        Ok(input_value - output_value)
+        validation::check_coinbase_value(self, params.block_reward(predicted_block_height), miner_fees)?;
 
+        Ok(())
     }
-
-
-            
-
-
-
 }
 
 impl Saveable for Block {
@@ -266,14 +146,14 @@ pub struct BlockHeader {
     pub nonce: u64,
     pub prev_block_hash: Hash,
     pub merkle_root: MerkleRoot,  // hash of the the merkle tree root derived from all of the transaction in this block
-    pub target: U256,   // a number , which ha to higher than the hash of this block for it to considered valid
+    pub bits: u32,   // the target in compact form, a number which has to be higher than the hash of this block for it to be considered valid
 
 }
 
 impl BlockHeader {
 
 
-    pub fn new( timestamp: DateTime<Utc>, nonce:u64, prev_block_hash: Hash, merkle_root: MerkleRoot, target: U256) -> Self {
+    pub fn new( timestamp: DateTime<Utc>, nonce:u64, prev_block_hash: Hash, merkle_root: MerkleRoot, bits: u32) -> Self {
       
 
       BlockHeader {
@@ -282,7 +162,7 @@ impl BlockHeader {
         nonce,
         prev_block_hash,
         merkle_root, 
-        target,
+        bits,
       }
 
     }
@@ -294,11 +174,35 @@ impl BlockHeader {
     }
 
 
+    // the target encoded in `bits`, invalid bits give a target of zero which no block meets
+
+    pub fn target(&self) -> U256 {
+
+        U256::from_compact(self.bits).unwrap_or_default()
+    }
+
+
+    // the expected number of hashes a miner has to try to find a block at this target
+    // the chain with the most cumulative work is the one everybody should follow
+
+    pub fn work(&self) -> U256 {
+
+        let target = self.target();
+
+        match target.checked_add(U256::one()) {
+
+            Some(divisor) => (!target / divisor).saturating_add(U256::one()),
+
+            None => U256::one(),
+        }
+    }
+
+
     pub fn mine(&mut self, steps: usize) -> bool {
 
         // if the block already matches target, return early 
 
-        if self.hash().matches_target(self.target) {
+        if self.hash().matches_target(self.target()) {
 
             return true;
         }
@@ -314,7 +218,7 @@ impl BlockHeader {
                 self.nonce = 0;
                 self.timestamp = Utc::now()  // why using timestamp as nonce
             }
-            if self.hash().matches_target(self.target) {
+            if self.hash().matches_target(self.target()) {
 
                 return true;
             }
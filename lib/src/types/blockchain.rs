@@ -1,12 +1,19 @@
 use bigdecimal::BigDecimal;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use super::{Block, Transaction, TransactionOutput};
+use super::{Block, BlockHeader, OutPoint, Transaction};
+use crate::amount::Amount;
 use crate::error::{BtcError, Result};
+use crate::mempool::{Mempool, MempoolEntry, MempoolView};
+use crate::params::ChainParams;
 use crate::sha256::Hash;
-use crate::util::MerkleRoot;
+use crate::store::BlockStore;
+use crate::utxo::{self, UtxoEntry, UtxoSet};
+use crate::validation::{self, ChainContext, Rule, RuleViolation, ValidationLevel};
 use crate::U256;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use crate::util::Saveable;
 use std::io::{
@@ -15,448 +22,2198 @@ Result as IoResult, Write,
 };
 
 
+// layout of a blockchain's data directory
+
+const CHAIN_STATE_FILE: &str = "chainstate.cbor";
+
+const BLOCKS_DIR: &str = "blocks";
+
+const UTXO_DB_FILE: &str = "utxos.redb";
+
+const MEMPOOL_FILE: &str = "mempool.cbor";
+
+
+// how many invalid blocks we remember, the oldest are forgotten first
+// a forgotten block is only refused later on, once its branch gets connected again
+
+const MAX_INVALID_BLOCKS: usize = 1_000;
+
+
+// what connecting a block changed, so disconnecting it can restore the exact previous state
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Blockchain {
+struct BlockUndo {
+
+    // the target before the block was connected
+    target: U256,
+
+    // the outputs the block spent, in the order they were spent
+    spent: Vec<(OutPoint, UtxoEntry)>,
+}
+
+
+// the part of the blockchain that is kept in memory and saved to the chain state file
+// block bodies and undo data are only in the block store, and the UTXO set in its own database
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ChainState {
+
+    // hash and header of every block of the active chain, indexed by height
+    chain: Vec<(Hash, BlockHeader)>,
 
-    blocks: Vec<Block>,
     target: U256,
-    utxos: HashMap<Hash, (bool, TransactionOutput)>,
 
-    #[serde(default, skip_serializing)]
-    mempool: Vec<(DateTime<Utc>, Transaction)>,
-   
-    
+    // headers of valid blocks that are not part of the active chain, keyed by their hash
+    // we keep them around in case their branch overtakes the active chain
+    side_blocks: HashMap<Hash, BlockHeader>,
+
+    // blocks that failed validation when their branch was connected, and the side blocks built on them,
+    // oldest first, they are refused right away when a peer sends them again
+    #[serde(default)]
+    invalid_blocks: VecDeque<Hash>,
+
+    // set when a storage error left the UTXO set out of line with the active chain,
+    // it is rebuilt before another block is connected, or when the blockchain is opened again
+    #[serde(default)]
+    utxos_stale: bool,
+
+    // height of every block of the active chain, keyed by its hash
+    // it is not saved, loading the chain state rebuilds it from `chain`
+    #[serde(skip)]
+    heights: HashMap<Hash, usize>,
+}
+
+
+// the mempool as it is saved when the node stops, every transaction with the time it arrived
+// parents come before their children, so the transactions can be added back in this order
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SavedMempool {
+
+    transactions: Vec<(DateTime<Utc>, Transaction)>,
+}
+
+
+#[derive(Debug)]
+pub struct Blockchain {
+
+    params: ChainParams,
+    data_dir: PathBuf,
+    store: BlockStore,
+    state: ChainState,
+    utxos: UtxoSet,
+
+    mempool: Mempool,
 }
 
 impl Blockchain {
 
-    pub fn new() -> Self {
+    // open the blockchain of the network described by `params` stored in `data_dir`,
+    // a new one starting with the network's genesis block is created if there is none
 
-        Blockchain { 
-            
-            blocks: vec![],
-            target:crate::MIN_TARGET,
-            utxos: HashMap::new(),
-            mempool: vec![],
+    pub fn open<P: AsRef<Path>>(data_dir: P, params: ChainParams) -> IoResult<Self> {
+
+        let data_dir = data_dir.as_ref().to_path_buf();
+
+        fs::create_dir_all(&data_dir)?;
+
+        let store = BlockStore::open(data_dir.join(BLOCKS_DIR))?;
+
+        let chain_state_path = data_dir.join(CHAIN_STATE_FILE);
+
+        let state = if chain_state_path.exists() {
+
+            ChainState::load_from_file(&chain_state_path)?
+
+        } else {
+
+            ChainState {
+                chain: vec![],
+                target: params.min_target,
+                side_blocks: HashMap::new(),
+                invalid_blocks: VecDeque::new(),
+                utxos_stale: false,
+                heights: HashMap::new(),
+            }
+        };
+
+        let utxos = UtxoSet::open(data_dir.join(UTXO_DB_FILE), utxo::DEFAULT_CACHE_CAPACITY)?;
 
-           
+        // a data directory belongs to exactly one network
 
+        if let Some((genesis_hash, _)) = state.chain.first() {
+
+            if *genesis_hash != params.genesis_hash() {
+
+                return Err(IoError::new(
+                    IoErrorKind::InvalidData,
+                    format!("{} does not contain a {} chain", data_dir.display(), params.network),
+                ));
+            }
         }
-    }
-    
 
-    // utxos
-    pub fn utxos(&self) -> &HashMap<Hash, (bool, TransactionOutput)> {
+        let mut blockchain = Blockchain {
+            mempool: Mempool::new(params.max_mempool_size),
+            params,
+            data_dir,
+            store,
+            state,
+            utxos,
+        };
 
-       &self.utxos
+        // if the UTXO set can not be moved to the tip, for example because undo data of a block it was at
+        // never made it to disk, it is recomputed from the blocks of the active chain
+
+        let reconciled = if blockchain.state.utxos_stale {
+
+            Err(IoError::other("the UTXO set does not match the chain").into())
+
+        } else {
+
+            blockchain.reconcile_utxos()
+        };
+
+        if let Err(e) = reconciled {
+
+            println!("failed to bring the UTXO set up to date, rebuilding it: {}", e);
+
+            blockchain.rebuild_utxos().map_err(|e| {
+
+                IoError::new(IoErrorKind::InvalidData, format!("failed to rebuild the UTXO set: {}", e))
+            })?;
+        }
+
+        if blockchain.state.chain.is_empty() {
+
+            let genesis = blockchain.params.genesis_block();
+
+            blockchain.add_block(genesis).map_err(|e| {
+
+                IoError::new(IoErrorKind::InvalidData, format!("failed to add the genesis block: {}", e))
+            })?;
+        }
+
+        blockchain.load_mempool();
+
+        Ok(blockchain)
     }
 
 
-    // target
+    // write everything to disk
+    // the block store is synced first, so nothing saved afterwards refers to a block that is not stored
+    // the UTXO set and the chain state are saved separately, if we crash in between
+    // reconcile_utxos brings them back in line on the next start
 
-    pub fn target(&self) -> U256 {
+    pub fn flush(&mut self) -> IoResult<()> {
+
+        self.flush_utxos()?;
 
-        self.target
+        self.state.save_to_file(self.data_dir.join(CHAIN_STATE_FILE))
     }
 
-    // blocks
 
-    pub fn blocks(&self) -> impl Iterator<Item = &Block> {
+    // write the UTXO set to its database
+    // the block store is synced first, a crash must never leave the database at a tip
+    // whose block or undo data did not make it to disk
 
-        self.blocks.iter()
+    fn flush_utxos(&mut self) -> IoResult<()> {
+
+        self.store.sync()?;
+
+        self.utxos.flush()
     }
 
-    // mempool 
 
-    pub fn mempool(&self) -> &[(DateTime<Utc>, Transaction)] {
+    // write the mempool to its own file, it is read back when the blockchain is opened again
 
-        &self.mempool
+    pub fn save_mempool(&self) -> IoResult<()> {
+
+        let saved = SavedMempool {
+            transactions: self
+                .mempool
+                .in_dependency_order()
+                .into_iter()
+                .map(|entry| (entry.time, entry.transaction.clone()))
+                .collect(),
+        };
+
+        saved.save_to_file(self.data_dir.join(MEMPOOL_FILE))
     }
 
 
-    // add a transaction to mempool
+    // add the saved mempool back, each transaction goes through add_to_mempool again
+    // the chain may have moved on since it was saved, whatever is too old or no longer valid is dropped
+    // and the outputs the rest spend are marked again
+    // losing the mempool is no reason not to start, an unreadable file leaves it empty
 
+    fn load_mempool(&mut self) {
 
-    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
+        let path = self.data_dir.join(MEMPOOL_FILE);
 
-        // validate transaction before insertion
-        // all inputs must known Utxos, and must be unique
+        if !path.exists() {
 
-        let mut known_inputs = HashSet::new();
+            return;
+        }
 
-        for input in &transaction.inputs {
+        let saved = match SavedMempool::load_from_file(&path) {
 
-            if !self.utxos.contains_key(&input.prev_transaction_output_hash) {
+            Ok(saved) => saved,
 
-                return Err(BtcError::InvalidTransaction);
-            }
+            Err(e) => {
 
-            if known_inputs.contains(&input.prev_transaction_output_hash) {
+                println!("failed to read the saved mempool, starting with an empty one: {}", e);
 
-                return Err(BtcError::InvalidTransaction);
+                return;
             }
+        };
 
-            known_inputs.insert(input.prev_transaction_output_hash);
-        }
+        let now = Utc::now();
 
-        // check if any of the utxos have the bool mark set to true 
-        // and if so , find the transaction that reference them in mempool
-        // remove it and set all the utxos it reference to false
-        
+        let max_age = chrono::Duration::seconds(self.params.max_mempool_transaction_age as i64);
 
-        for input in &transaction.inputs{ 
+        let total = saved.transactions.len();
 
-            if let Some((true, _)) = self.utxos.get(&input.prev_transaction_output_hash) { //  do the matching pattern run if the return value is true
+        let mut dropped = 0;
 
+        for (time, transaction) in saved.transactions {
 
-                // find the transaction that references the utxo , we are trying to reference
+            if now - time > max_age || self.accept_to_mempool(transaction, time).is_err() {
 
-                let referencing_transaction = self.mempool  
-                    .iter()
-                    .enumerate()
-                    .find(
-                            |(_, (_, transaction)) | {
+                dropped += 1;
+            }
+        }
 
-                                transaction.outputs.iter()
-                                    .any(|output | {
+        println!("loaded {} mempool transactions, dropped {} expired or invalid ones", total - dropped, dropped);
+    }
 
-                                        output.hash() == input.prev_transaction_output_hash
-                                    })
-                            }
-                    );
 
-                // if we have found one, unmark all of its UTXOs
+    // move the UTXO set to the tip of the active chain
+    // it can be behind or on another branch when the node stopped between saving the UTXO set
+    // and the chain state, so we roll it back with the undo data until it is on the active chain,
+    // and then apply the blocks it is missing
 
-                if let Some(( idx, (_, referencing_transaction))) = referencing_transaction {
+    fn reconcile_utxos(&mut self) -> Result<()> {
 
-                    for input in &referencing_transaction.inputs {
+        if self.utxos.tip() == self.tip_hash() {
 
-                        // set all utxos from this transaction to false
+            return Ok(());
+        }
 
+        while self.utxos.tip() != Hash::zero() && self.block_position(&self.utxos.tip()).is_none() {
 
-                        self.utxos.entry(input.prev_transaction_output_hash).and_modify(|(marked, _)| {
+            let hash = self.utxos.tip();
 
-                            *marked = false;
-                        });           
-                    }
+            let (block, undo) = self.stored_block_and_undo(&hash)?;
 
+            self.disconnect_utxos(&block, undo.spent)?;
+            self.utxos.set_tip(block.header.prev_block_hash);
+        }
 
-                    // remove the transaction from the mempool 
+        let start = match self.block_position(&self.utxos.tip()) {
 
-                    self.mempool.remove(idx);
-                
-                } else  {
+            Some(position) => position + 1,
 
-                    // if some how  there is no matching - set this utxo to false
+            None => 0,
+        };
 
-                    self.utxos.entry(input.prev_transaction_output_hash).and_modify(|(marked, _)| {
+        if start < self.state.chain.len() {
 
-                        *marked = false;
-                    });
-                
-                }
+            println!("bringing the UTXO set from height {} to {}", start, self.state.chain.len());
+        }
+
+        for height in start..self.state.chain.len() {
+
+            let hash = self.state.chain[height].0;
+
+            let block = self.store.get_block(&hash)?.ok_or_else(|| {
+
+                IoError::new(IoErrorKind::NotFound, "block of the active chain is not stored")
+            })?;
 
+            self.connect_utxos(&block, height as u64)?;
+            self.utxos.set_tip(hash);
 
+            if self.utxos.is_full() {
 
+                self.flush_utxos()?;
             }
         }
 
+        self.flush_utxos()?;
 
+        Ok(())
+    }
 
 
-        let all_inputs = transaction.inputs
-            .iter()
-            .map(|input| {
-            self.utxos
-            .get(
-            &input.prev_transaction_output_hash,
-            )
-            .expect("BUG: impossible")
-            .1
-            .value
-            })
-            .sum::<u64>();
+    pub fn params(&self) -> &ChainParams {
 
-        let all_outputs = transaction.outputs
-            .iter()
-            .map(|output| output.value)
-            .sum();
-            if all_inputs < all_outputs {
-            return Err(BtcError::InvalidTransaction);
-            }
+        &self.params
+    }
 
-        self.mempool.push((Utc::now(), transaction));
 
-        // sort by miner fee
+    // utxos
+    pub fn utxos(&self) -> &UtxoSet {
 
-        self.mempool.sort_by_key( |( _, transaction) | {
+       &self.utxos
+    }
 
 
-            let all_inputs  = transaction.inputs
-                .iter()
-                .map(|input| {
-                    self.utxos
-                        .get(&input.prev_transaction_output_hash)
-                        .expect("Bug: impossible")
-                        .1  
-                        .value
+    // target
 
+    pub fn target(&self) -> U256 {
 
-                })
-                .sum::<u64>();
+        self.state.target
+    }
 
-            let all_outputs: u64 = transaction.outputs
-                .iter()
-                .map(|output  | output.value)
-                .sum();
+    // headers of the active chain, from the genesis block to the tip
 
-            let miner_fee = all_inputs - all_outputs;
+    pub fn headers(&self) -> impl DoubleEndedIterator<Item = &BlockHeader> {
 
-            miner_fee
-            
-        });
+        self.state.chain.iter().map(|(_, header)| header)
+    }
 
-        Ok(())
+    // hash of the last block of the active chain, zero if there is none yet
+
+    pub fn tip_hash(&self) -> Hash {
+
+        self.state.chain.last().map(|(hash, _)| *hash).unwrap_or(Hash::zero())
     }
 
 
+    // total proof of work of the active chain, nodes follow the chain with the most of it
 
+    pub fn chain_work(&self) -> U256 {
 
-    // cleanup mempool - remove transaction older than Max mempool age defined in lib.rs
+        self.state.chain.iter().fold(U256::zero(), |work, (_, header)| work + header.work())
+    }
 
 
-    pub fn cleanup_mempool (&mut self) {
+    // whether the block is part of the active chain or of a side branch
 
-        let now = Utc::now();
+    pub fn contains_block(&self, hash: &Hash) -> bool {
 
-        let mut utxo_hashes_to_unmark: Vec<Hash> = vec![];
+        self.state.side_blocks.contains_key(hash) || self.block_position(hash).is_some()
+    }
 
-        self.mempool.retain(|(timestamp, transaction )| {
+    // read the block at the given height of the active chain from the block store
 
-            if now - *timestamp > chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64) {
+    pub fn block_at(&self, height: u64) -> Result<Option<Block>> {
 
-                // push all  the utxo to unmarke to the vector
-                // so we can unmark them later
+        match self.state.chain.get(height as usize) {
 
+            Some((hash, _)) => self.block(hash),
 
-                utxo_hashes_to_unmark.extend(transaction.inputs.iter().map(|input| {    
+            None => Ok(None),
+        }
+    }
 
-                        input.prev_transaction_output_hash
+    // read any block we know, including the ones on side branches
 
-                }));
-                false
-            } else  {
+    pub fn block(&self, hash: &Hash) -> Result<Option<Block>> {
 
-                true
-            }
+        Ok(self.store.get_block(hash)?)
+    }
 
+    // transactions waiting to be mined, highest fee rate first
 
-        });
+    pub fn mempool(&self) -> &Mempool {
 
-        // unmark all of the UTXOS
+        &self.mempool
+    }
 
 
-        for hash in utxo_hashes_to_unmark {
+    // add a transaction to mempool
 
-            self.utxos.entry(hash).and_modify(
 
-                |(marked, _)| {
+    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
 
-                    *marked = false;
-                },
-            );
-        }
+        self.accept_to_mempool(transaction, Utc::now())
+    }
 
 
+    // add a transaction that arrived at `time` to the mempool
 
+    fn accept_to_mempool(&mut self, transaction: Transaction, time: DateTime<Utc>) -> Result<()> {
 
-    }
+        if self.mempool.contains(&transaction.hash()) {
 
+            return Err(RuleViolation::standalone(Rule::Policy, "transaction is already in the mempool").into());
+        }
 
+        // validate transaction before insertion, with the same rules a block applies to it plus policy
+        // the transaction can at the earliest go into the next block, and it can spend
+        // the outputs of other mempool transactions, they would go into the block before it
 
+        let view = MempoolView {
+            utxos: &self.utxos,
+            mempool: &self.mempool,
+            height: self.blocks_height(),
+        };
 
+        let fee = transaction.validate(&view, self.blocks_height(), &self.params, ValidationLevel::Policy)?;
 
+        let entry = MempoolEntry::new(transaction, fee, time);
 
-    pub fn rebuild_utxos(&mut self)  {
+        // once the mempool has been full, it only takes transactions paying more than the ones it evicted
 
-        for block in &self.blocks {
+        if entry.fee < self.mempool.min_fee_rate().fee(entry.size) {
 
-            for transaction in &block.transactions {
+            return Err(RuleViolation::standalone(Rule::Policy, "fee rate is below the mempool minimum").into());
+        }
 
-                for input in &transaction.inputs {
+        // mempool transactions spending the same outputs are replaced, together with their descendants,
+        // if the new transaction pays enough more for it
 
-                    self.utxos.remove(&input.prev_transaction_output_hash);
-                }
+        let conflicts = self.mempool.conflicts(&entry.transaction);
 
-                for output in transaction.outputs.iter() {
+        if !conflicts.is_empty() {
 
-                    self.utxos.insert(output.hash(), (false, output.clone()));   
-                }
-            }
+            self.mempool.check_replacement(&entry, &conflicts)?;
         }
-    }
 
+        self.mempool.check_chain_limits(&entry.transaction, entry.size, &conflicts)?;
 
+        // the new transaction can still be evicted right away when the mempool is full,
+        // the replaced transactions must not be gone then, so we keep the mempool as it was
 
+        let before_replacement = (!conflicts.is_empty()).then(|| self.mempool.clone());
 
-    pub fn add_block(&mut self, block: Block) -> Result<()> {
+        let mut replaced = vec![];
 
-         
-         if self.blocks.is_empty() {
+        for txid in &conflicts {
 
-            if block.header.prev_block_hash != Hash::zero() {
+            for entry in self.mempool.remove_with_descendants(txid) {
 
-                println!("zero hash");
+                self.unmark_spends(&entry);
 
-                return Err(BtcError::InvalidBlock);
+                replaced.push(entry);
             }
-         } else {
+        }
 
-                let last_block = self.blocks.last().unwrap();
+        // the outputs it spends are now taken by a mempool transaction
 
-                if block.header.prev_block_hash != last_block.hash() {
+        self.mark_spends(&entry);
 
-                    println!("prev hash is wrong");
-                    return Err(BtcError::InvalidBlock);
-                }
+        let txid = entry.txid();
 
-                if !block.header.hash().matches_target(block.header.target) {
+        let evicted = self.mempool.insert(entry);
 
-                    println!("does not match target");
-                    return Err(BtcError::InvalidBlock);
+        for entry in &evicted {
 
-                }
+            self.unmark_spends(entry);
+        }
 
-                let calculated_merkle_root = MerkleRoot::calculate(&block.transactions);
+        let rejected = evicted.iter().any(|entry| entry.txid() == txid);
 
-                if calculated_merkle_root != block.header.merkle_root {
+        match before_replacement {
 
-                    println!("invalid merkle root");
-                    return Err(BtcError::InvalidBlock);
-                }
+            // nothing is replaced after all, the transactions it replaced or evicted stay
+
+            Some(mempool) if rejected => {
+
+                self.mempool = mempool;
 
-                if block.header.timestamp <= last_block.header.timestamp {
+                for entry in replaced.iter().chain(evicted.iter().filter(|entry| entry.txid() != txid)) {
 
-                   block.verify_transaction(self.blocks_height(), &self.utxos)?;
+                    self.mark_spends(entry);
                 }
+            }
 
+            _ => {
 
-         }
+                for entry in &replaced {
 
-         // Remove transaction from mempool that are now in the block
-        
-        let block_transactions: HashSet<_> = block.transactions.iter().map(|tx| tx.hash()).collect();
+                    println!("transaction {} replaced by {}", entry.txid(), txid);
+                }
+
+                for entry in &evicted {
 
-        self.mempool.retain(| (_,  tx)|  {
+                    println!("mempool is full, evicted transaction {} paying {}", entry.txid(), entry.fee_rate());
+                }
+            }
+        }
 
-            !block_transactions.contains(&tx.hash())
+        if rejected {
+
+            return Err(RuleViolation::standalone(Rule::Policy, "mempool is full").into());
+        }
 
-        });
-        
-        self.blocks.push(block);
-        self.try_adjust_target();
         Ok(())
     }
 
 
-    pub fn blocks_height(&self) -> u64 {
-        
-        self.blocks.len() as u64
+    // the outputs a transaction in the mempool spends are taken
+
+    fn mark_spends(&mut self, entry: &MempoolEntry) {
+
+        for input in &entry.transaction.inputs {
+
+            self.utxos.set_marked(input.previous_output, true);
+        }
     }
 
-    pub fn try_adjust_target(&mut self) {
 
-        if self.blocks.is_empty() {
-            
-            return ;
+    // the outputs a transaction that left the mempool spent are free again
+
+    fn unmark_spends(&mut self, entry: &MempoolEntry) {
+
+        for input in &entry.transaction.inputs {
+
+            self.utxos.set_marked(input.previous_output, false);
         }
+    }
 
-        if self.blocks.len() % crate::DIFFICULTY_UPDATE_INTERVAL as usize != 0 {
 
-            return;
+    // cleanup mempool - remove transaction older than Max mempool age defined in lib.rs
 
+
+    pub fn cleanup_mempool (&mut self) {
+
+        let now = Utc::now();
+
+        let max_age = chrono::Duration::seconds(self.params.max_mempool_transaction_age as i64);
+
+        let expired = self.mempool.remove_where(|entry| now - entry.time > max_age);
+
+        // unmark all of the UTXOS
+
+        for entry in &expired {
+
+            self.unmark_spends(entry);
         }
+    }
 
-        let start_time = self.blocks[self.blocks.len() - crate::DIFFICULTY_UPDATE_INTERVAL as usize].header.timestamp;
 
-        let end_time = self.blocks.last().unwrap().header.timestamp;
 
-        let time_diff = end_time - start_time;
 
-        let time_diff_seconds  = time_diff.num_seconds();
 
-        let target_seconds = crate::IDEAL_BLOCK_TIME * crate::DIFFICULTY_UPDATE_INTERVAL;
 
+    // recompute the UTXO set and the undo data by replaying every block of the active chain
+    // adding blocks keeps both up to date, this is only needed to repair a damaged chain state
 
-        // multiply the current target by the actual time divided by the ideal time
-        // Target is difficulty 
+    pub fn rebuild_utxos(&mut self) -> IoResult<()> {
 
-        let new_target = BigDecimal::parse_bytes(&self.target.to_string().as_bytes(), 10).expect("Bug: impossible")
-                                                    * (BigDecimal::from(time_diff_seconds) / BigDecimal::from(target_seconds));
+        self.utxos.clear()?;
 
-        // cut off decimal point and everything after it from string representation of new target
+        let chain = self.state.truncate(0);
+        let mempool = std::mem::replace(&mut self.mempool, Mempool::new(self.params.max_mempool_size));
 
+        self.state.target = self.params.min_target;
 
-        let new_target_str = new_target.to_string().split('.').next().expect("expected a decimal point").to_owned();
+        let mut blocks = chain.into_iter();
 
-        let new_target: U256 = U256::from_str_radix(&new_target_str, 10).expect("Bug: Impossible");
+        while let Some((hash, header)) = blocks.next() {
 
-        // clamp new_target to be within the range of 4 * self.target adn self.target / 4 
+            // a block that is missing from the store, or spends an output that does not exist,
+            // can not be applied, and neither can anything building on it
+            // a storage error says nothing about the blocks, the chain is kept and we try again later
 
-        let new_target = if new_target < self.target / 4 {
+            let result = match self.store.get_block(&hash) {
 
-            self.target / 4
+                Ok(Some(block)) => self.push_block(block),
 
-        }  else if new_target > self.target * 4 {
-             
-            self.target * 4
+                Ok(None) => Err(BtcError::InvalidBlock),
 
-        } else {
+                Err(e) => Err(e.into()),
+            };
 
-            new_target
-        };
+            match result {
+
+                Ok(()) => {}
+
+                Err(BtcError::Storage(e)) => {
+
+                    self.state.push(hash, header);
 
-        // finally, we need to ensure that we do not decrease the target below minimum target
+                    for (hash, header) in blocks {
 
-        // if the new target is more than the min-target , set it to the minimum target
+                        self.state.push(hash, header);
+                    }
+
+                    self.mempool = mempool;
+                    self.state.utxos_stale = true;
 
-        self.target = new_target.min(crate::MIN_TARGET);
+                    return Err(e);
+                }
 
+                Err(_) => {
 
+                    println!("block at height {} does not apply, dropping the rest of the chain", self.state.chain.len());
+                    break;
+                }
+            }
+        }
 
+        self.restore_mempool(mempool);
 
+        self.flush_utxos()?;
 
+        self.state.utxos_stale = false;
 
+        Ok(())
     }
 
-}
 
+    // spend the inputs and create the outputs of a block at `height`, returning the outputs it spent
+    // nothing is changed if one of the inputs is not an unspent output
 
-impl Saveable for Blockchain {
+    fn connect_utxos(&mut self, block: &Block, height: u64) -> Result<Vec<(OutPoint, UtxoEntry)>> {
 
+        // a transaction can spend the outputs of the ones before it in the block
 
-    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        let mut spending = HashSet::new();
+        let mut created = HashSet::new();
 
+        for transaction in &block.transactions {
 
-        ciborium::de::from_reader(reader).map_err(|_| {
+            for input in &transaction.inputs {
 
-            IoError::new(IoErrorKind::InvalidData,
-             "failed to deserialize the data"
-            )
-        }) 
-    }
+                let unspent = created.contains(&input.previous_output) || self.utxos.contains(&input.previous_output)?;
 
+                if !unspent || !spending.insert(input.previous_output) {
 
-    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+                    println!("block spends an output that is not unspent");
+                    return Err(BtcError::InvalidBlock);
+                }
+            }
 
-        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            created.extend((0..transaction.outputs.len()).map(|vout| transaction.outpoint(vout)));
+        }
 
-            IoError::new(IoErrorKind::InvalidData,
-             "failed to serialize the data"
-            )
-        }) 
+        let mut spent = vec![];
+
+        for transaction in &block.transactions {
+
+            for input in &transaction.inputs {
+
+                let entry = self.utxos
+                    .remove(&input.previous_output)?
+                    .expect("BUG: input checked above");
+
+                spent.push((input.previous_output, entry));
+            }
+
+            let txid = transaction.hash();
+
+            for (vout, output) in transaction.outputs.iter().enumerate() {
+
+                self.utxos.insert(OutPoint { txid, vout: vout as u32 }, UtxoEntry {
+                    output: output.clone(),
+                    height,
+                    coinbase: transaction.is_coinbase(),
+                });
+            }
+        }
+
+        Ok(spent)
     }
 
 
+    // the reverse of connect_utxos
+
+    // transactions are undone last to first, as one can spend the outputs of an earlier one
+
+    fn disconnect_utxos(&mut self, block: &Block, mut spent: Vec<(OutPoint, UtxoEntry)>) -> IoResult<()> {
+
+        for transaction in block.transactions.iter().rev() {
+
+            for vout in 0..transaction.outputs.len() {
+
+                self.utxos.remove(&transaction.outpoint(vout))?;
+            }
+
+            for _ in &transaction.inputs {
+
+                if let Some((outpoint, entry)) = spent.pop() {
+
+                    self.utxos.insert(outpoint, entry);
+                }
+            }
+        }
 
-}
\ No newline at end of file
+        Ok(())
+    }
+
+
+    // read a connected block and its undo data back from the block store
+
+    fn stored_block_and_undo(&self, hash: &Hash) -> Result<(Block, BlockUndo)> {
+
+        match (self.store.get_block(hash)?, self.store.get_undo(hash)?) {
+
+            (Some(block), Some(undo)) => Ok((block, undo)),
+
+            _ => Err(IoError::new(IoErrorKind::NotFound, "block or its undo data is not stored").into()),
+        }
+    }
+
+
+    // remove the tip of the active chain and restore the UTXO set and target from before it was added
+    // returns the removed block, its transactions are not put back into the mempool
+
+    pub fn disconnect_tip(&mut self) -> Result<Option<Block>> {
+
+        let Some((hash, _)) = self.state.chain.last() else {
+
+            return Ok(None);
+        };
+
+        let (block, undo) = self.stored_block_and_undo(hash)?;
+
+        self.state.pop();
+
+        self.disconnect_utxos(&block, undo.spent)?;
+
+        self.utxos.set_tip(block.header.prev_block_hash);
+        self.state.target = undo.target;
+
+        self.remove_invalid_mempool_transactions();
+
+        Ok(Some(block))
+    }
+
+
+    // drop mempool transactions that spend outputs which are no longer unspent,
+    // or coinbase outputs that are no longer mature now that the chain got shorter
+
+    fn remove_invalid_mempool_transactions(&mut self) {
+
+        let utxos = &self.utxos;
+        let mempool = &self.mempool;
+        let height = self.state.chain.len() as u64;
+        let coinbase_maturity = self.params.coinbase_maturity;
+
+        // an input can also spend the output of another mempool transaction
+        // a transaction we can not check against the UTXO set is dropped as well
+
+        let invalid: Vec<Hash> = mempool
+            .iter()
+            .filter(|entry| {
+
+                !entry.transaction.inputs.iter().all(|input| {
+
+                    mempool.contains(&input.previous_output.txid) || matches!(
+                        utxos.get(&input.previous_output),
+                        Ok(Some((_, entry))) if entry.is_mature(height, coinbase_maturity)
+                    )
+                })
+            })
+            .map(|entry| entry.txid())
+            .collect();
+
+        // the descendants go as well, they spend outputs of the invalid ones
+
+        for txid in invalid {
+
+            for entry in self.mempool.remove_with_descendants(&txid) {
+
+                self.unmark_spends(&entry);
+            }
+        }
+    }
+
+
+    // put back a mempool that was set aside, marking what it spends again
+    // and dropping whatever the chain no longer allows
+
+    fn restore_mempool(&mut self, mempool: Mempool) {
+
+        self.mempool = mempool;
+
+        for entry in self.mempool.iter() {
+
+            for input in &entry.transaction.inputs {
+
+                self.utxos.set_marked(input.previous_output, true);
+            }
+        }
+
+        self.remove_invalid_mempool_transactions();
+    }
+
+
+
+
+    // add a block to the chain
+    // a block that does not build on the tip is kept as part of a side branch,
+    // and if that branch ends up with more work than the active chain we switch over to it
+
+    pub fn add_block(&mut self, block: Block) -> Result<()> {
+
+        // nothing can be validated against a UTXO set that does not match the chain, see recover_chain
+
+        if self.state.utxos_stale {
+
+            self.rebuild_utxos()?;
+        }
+
+        if block.header.prev_block_hash == self.tip_hash() {
+
+            return self.connect_block(block);
+        }
+
+        self.add_side_block(block)
+    }
+
+
+    // extend the active chain with a block building on its tip
+
+    fn connect_block(&mut self, block: Block) -> Result<()> {
+
+         if self.state.chain.is_empty() {
+
+            // the only block without a parent is the genesis block of our network
+
+            if block.hash() != self.params.genesis_hash() {
+
+                println!("not the genesis block");
+
+                return Err(BtcError::InvalidBlock);
+            }
+         } else {
+
+            // every consensus rule, see validation.rs
+
+            let context = ChainContext {
+                prev_block_hash: self.tip_hash(),
+                height: self.blocks_height(),
+                median_time_past: validation::median_time_past(self.headers()).expect("BUG: chain is not empty"),
+                target: self.target(),
+            };
+
+            validation::validate_block(&block, &context, &self.utxos, &self.params)?;
+         }
+
+         self.push_block(block)
+    }
+
+
+    // apply a validated block on top of the active chain
+
+    fn push_block(&mut self, block: Block) -> Result<()> {
+
+        let hash = block.hash();
+
+        self.store.put_block(&block)?;
+
+        let spent = self.connect_utxos(&block, self.blocks_height())?;
+
+        let undo = BlockUndo {
+            target: self.state.target,
+            spent,
+        };
+
+        if let Err(e) = self.store.put_undo(hash, &undo) {
+
+            self.disconnect_utxos(&block, undo.spent)?;
+
+            return Err(e.into());
+        }
+
+        self.utxos.set_tip(hash);
+
+        // once enough changes have piled up they are written out, the tip goes with them
+        // so the database never holds part of a block
+
+        if self.utxos.is_full() {
+
+            self.flush_utxos()?;
+        }
+
+         // Remove transaction from mempool that are now in the block
+        
+        for transaction in &block.transactions {
+
+            self.mempool.remove(&transaction.hash());
+        }
+
+        // and the ones that conflict with it
+
+        self.remove_invalid_mempool_transactions();
+        
+        self.state.push(hash, block.header);
+        self.try_adjust_target();
+        Ok(())
+    }
+
+
+    // store a block that does not build on our tip, and reorganize if its branch now has the most work
+
+    fn add_side_block(&mut self, block: Block) -> Result<()> {
+
+        let hash = block.hash();
+
+        if self.contains_block(&hash) {
+
+            println!("block already known");
+            return Err(BtcError::InvalidBlock);
+        }
+
+        // the work is checked before we remember anything about the block, so that costs a peer as much
+        // as mining a block would
+
+        self.check_work(&block.header)?;
+
+        if self.state.invalid_blocks.contains(&hash) {
+
+            return Err(RuleViolation::block(Rule::HeaderLinkage, "block is known to be invalid").into());
+        }
+
+        // a block building on an invalid one is invalid as well, it is refused the same way
+        // every time, so there is no need to remember it
+
+        if self.state.invalid_blocks.contains(&block.header.prev_block_hash) {
+
+            return Err(RuleViolation::block(Rule::HeaderLinkage, "previous block is invalid").into());
+        }
+
+        // we only accept side blocks whose parent we know, otherwise we could not tell
+        // where the branch forks off the active chain
+        // every chain starts with the same genesis block, so a side block always has a parent
+
+        let prev_block_hash = block.header.prev_block_hash;
+
+        if !self.contains_block(&prev_block_hash) {
+
+            return Err(RuleViolation::block(Rule::HeaderLinkage, "previous block is unknown").into());
+        }
+
+        // everything that does not depend on the chain state is checked right away,
+        // the transactions are verified once the branch gets connected
+
+        validation::check_size(&block)?;
+
+        validation::check_merkle_root(&block)?;
+
+        // walk back from the new block until we reach the active chain
+
+        let mut branch = vec![hash];
+        let mut branch_headers = vec![block.header.clone()];
+        let mut branch_work = block.header.work();
+        let mut cursor = prev_block_hash;
+
+        let fork_height = loop {
+
+            if let Some(position) = self.block_position(&cursor) {
+
+                break position + 1;
+            }
+
+            let Some(header) = self.state.side_blocks.get(&cursor) else {
+
+                return Err(RuleViolation::block(Rule::HeaderLinkage, "side branch does not lead back to the active chain").into());
+            };
+
+            branch.push(cursor);
+            branch_headers.push(header.clone());
+            branch_work += header.work();
+            cursor = header.prev_block_hash;
+        };
+
+        branch.reverse();
+        branch_headers.reverse();
+
+        // the branch has to carry the targets it would have on the active chain, otherwise cheap blocks
+        // on an old fork could pile up on disk
+
+        let height = fork_height + branch.len() - 1;
+
+        validation::check_target(&block.header, self.side_target(height, fork_height, &branch_headers))?;
+
+        self.store.put_block(&block)?;
+
+        self.state.side_blocks.insert(hash, block.header);
+
+        let active_work = self.state.chain[fork_height..]
+            .iter()
+            .fold(U256::zero(), |work, (_, header)| work + header.work());
+
+        // on a tie we stay with the chain we saw first
+
+        if branch_work > active_work {
+
+            self.reorganize(fork_height, branch)?;
+        }
+
+        Ok(())
+    }
+
+
+    // the target a block at `height` on a side branch has to carry, the branch forks off after
+    // the first `fork_height` blocks and `branch` holds its headers in order
+    // it changes like on the active chain, once every difficulty period based on how long the last one took
+
+    fn side_target(&self, height: usize, fork_height: usize, branch: &[BlockHeader]) -> U256 {
+
+        let header_at = |height: usize| -> &BlockHeader {
+
+            if height < fork_height {
+
+                &self.state.chain[height].1
+
+            } else {
+
+                &branch[height - fork_height]
+            }
+        };
+
+        let parent = header_at(height - 1);
+
+        let interval = self.params.difficulty_update_interval as usize;
+
+        if self.params.adjust_difficulty && height.is_multiple_of(interval) {
+
+            retarget(&self.params, parent.target(), header_at(height - interval), parent)
+
+        } else {
+
+            parent.target()
+        }
+    }
+
+
+    // the checks of a header that need nothing but the header: its hash meets the target it carries,
+    // and that target is not easier than the network allows
+    // a peer can not get us to do any work for a block that fails them without mining it
+
+    pub fn check_work(&self, header: &BlockHeader) -> Result<()> {
+
+        validation::check_proof_of_work(header)?;
+
+        if header.target() > self.params.min_target {
+
+            return Err(RuleViolation::block(Rule::ProofOfWork, "target is above the network minimum").into());
+        }
+
+        Ok(())
+    }
+
+
+    // switch the active chain over to `branch`, which forks off after the first `fork_height` blocks
+    // if a block of the new branch turns out to be invalid, the old chain is restored
+
+    fn reorganize(&mut self, fork_height: usize, branch: Vec<Hash>) -> Result<()> {
+
+        println!("reorganizing: disconnecting {} blocks, connecting {}", self.state.chain.len() - fork_height, branch.len());
+
+        let old_mempool = self.mempool.clone();
+
+        let old_blocks = self.state.chain[fork_height..].to_vec();
+
+        let mut disconnected = vec![];
+
+        while self.state.chain.len() > fork_height {
+
+            let block = match self.disconnect_tip() {
+
+                Ok(block) => block.expect("BUG: chain shorter than fork height"),
+
+                Err(e) => return Err(self.recover_chain(fork_height, old_blocks, old_mempool, e)),
+            };
+
+            self.state.side_blocks.insert(block.hash(), block.header.clone());
+
+            disconnected.push(block);
+        }
+
+        disconnected.reverse();
+
+        for (idx, hash) in branch.iter().enumerate() {
+
+            let result = match self.store.get_block(hash) {
+
+                Ok(Some(block)) => {
+
+                    self.state.side_blocks.remove(hash);
+
+                    self.connect_block(block)
+                }
+
+                Ok(None) => Err(IoError::new(IoErrorKind::NotFound, "side block is not stored").into()),
+
+                Err(e) => Err(e.into()),
+            };
+
+            if let Err(e) = result {
+
+                // a storage error can leave the UTXO set half way through a block
+
+                if let BtcError::Storage(_) = e {
+
+                    return Err(self.recover_chain(fork_height, old_blocks, old_mempool, e));
+                }
+
+                // forget the invalid block and everything building on it, on this branch or any other,
+                // the blocks before it stay around as a side branch
+
+                self.discard_side_blocks(branch[idx], is_permanent(&e));
+
+                if let Err(rollback_error) = self.restore_chain(fork_height, disconnected) {
+
+                    return Err(self.recover_chain(fork_height, old_blocks, old_mempool, rollback_error));
+                }
+
+                self.restore_mempool(old_mempool);
+
+                return Err(e);
+            }
+        }
+
+        // the transactions of the disconnected blocks go back to the mempool, together with
+        // the ones that were pending, unless the new branch already contains or conflicts with them
+        // parents have to go in before their children
+
+        let pending = std::mem::replace(&mut self.mempool, Mempool::new(self.params.max_mempool_size));
+
+        for entry in pending.iter() {
+
+            self.unmark_spends(entry);
+        }
+
+        let transactions: Vec<Transaction> = disconnected
+            .into_iter()
+            .flat_map(|block| block.transactions.into_iter().skip(1))
+            .chain(old_mempool.in_dependency_order().into_iter().map(|entry| entry.transaction.clone()))
+            .collect();
+
+        for transaction in transactions {
+
+            let _ = self.add_to_mempool(transaction);
+        }
+
+        Ok(())
+    }
+
+
+    // undo a reorganization that failed, the blocks of the new branch are disconnected
+    // and the `disconnected` ones, oldest first, connected again
+
+    fn restore_chain(&mut self, fork_height: usize, disconnected: Vec<Block>) -> Result<()> {
+
+        while self.state.chain.len() > fork_height {
+
+            let block = self.disconnect_tip()?.expect("BUG: chain shorter than fork height");
+
+            self.state.side_blocks.insert(block.hash(), block.header);
+        }
+
+        for block in disconnected {
+
+            self.state.side_blocks.remove(&block.hash());
+
+            self.push_block(block)?;
+        }
+
+        Ok(())
+    }
+
+
+    // after a storage error part way through a reorganization, the UTXO set may match neither branch
+    // the active chain is set back to `old_blocks` after the first `fork_height` blocks, and the UTXO set
+    // recomputed from it, right away if the storage allows, otherwise before the next block or on the next start
+    // returns `error`, the reason the reorganization failed
+
+    fn recover_chain(&mut self, fork_height: usize, old_blocks: Vec<(Hash, BlockHeader)>, mempool: Mempool, error: BtcError) -> BtcError {
+
+        println!("storage error during a reorganization, rebuilding the UTXO set: {}", error);
+
+        let new_blocks = self.state.truncate(fork_height);
+
+        for (hash, header) in new_blocks {
+
+            self.state.side_blocks.insert(hash, header);
+        }
+
+        for (hash, _) in &old_blocks {
+
+            self.state.side_blocks.remove(hash);
+        }
+
+        for (hash, header) in old_blocks {
+
+            self.state.push(hash, header);
+        }
+
+        self.mempool = mempool;
+
+        self.state.utxos_stale = true;
+
+        if let Err(e) = self.rebuild_utxos() {
+
+            println!("failed to rebuild the UTXO set, it is rebuilt later: {}", e);
+        }
+
+        error
+    }
+
+
+    // remove `hash` and every side block descending from it
+    // with `invalid` they are remembered as invalid, so they are not accepted again
+
+    fn discard_side_blocks(&mut self, hash: Hash, invalid: bool) {
+
+        let mut discarded = HashSet::from([hash]);
+
+        // side blocks are not kept in order, so we go over them until no more descendants turn up
+
+        loop {
+
+            let descendants: Vec<Hash> = self
+                .state
+                .side_blocks
+                .iter()
+                .filter(|(hash, header)| !discarded.contains(*hash) && discarded.contains(&header.prev_block_hash))
+                .map(|(hash, _)| *hash)
+                .collect();
+
+            if descendants.is_empty() {
+
+                break;
+            }
+
+            discarded.extend(descendants);
+        }
+
+        for hash in &discarded {
+
+            self.state.side_blocks.remove(hash);
+        }
+
+        if invalid {
+
+            self.state.invalid_blocks.extend(discarded);
+
+            let excess = self.state.invalid_blocks.len().saturating_sub(MAX_INVALID_BLOCKS);
+
+            self.state.invalid_blocks.drain(..excess);
+        }
+    }
+
+
+    // position of the block with the given hash in the active chain
+
+    fn block_position(&self, hash: &Hash) -> Option<usize> {
+
+        self.state.heights.get(hash).copied()
+    }
+
+
+    pub fn blocks_height(&self) -> u64 {
+        
+        self.state.chain.len() as u64
+    }
+
+
+    // block reward for the next block, in satoshis
+
+    pub fn calculate_block_reward(&self) -> Amount {
+
+        self.params.block_reward(self.blocks_height())
+    }
+
+    pub fn try_adjust_target(&mut self) {
+
+        let chain = &self.state.chain;
+
+        if chain.is_empty() {
+            
+            return ;
+        }
+
+        if !self.params.adjust_difficulty {
+
+            return;
+        }
+
+        if !chain.len().is_multiple_of(self.params.difficulty_update_interval as usize) {
+
+            return;
+
+        }
+
+        let first = &chain[chain.len() - self.params.difficulty_update_interval as usize].1;
+
+        let last = &chain.last().unwrap().1;
+
+        self.state.target = retarget(&self.params, self.state.target, first, last);
+
+
+
+
+
+
+    }
+
+}
+
+
+impl ChainState {
+
+    // add a block on top of the active chain
+
+    fn push(&mut self, hash: Hash, header: BlockHeader) {
+
+        self.heights.insert(hash, self.chain.len());
+        self.chain.push((hash, header));
+    }
+
+
+    // remove the tip of the active chain
+
+    fn pop(&mut self) -> Option<(Hash, BlockHeader)> {
+
+        let (hash, header) = self.chain.pop()?;
+
+        self.heights.remove(&hash);
+
+        Some((hash, header))
+    }
+
+
+    // cut the active chain down to its first `height` blocks, the removed blocks are returned in order
+
+    fn truncate(&mut self, height: usize) -> Vec<(Hash, BlockHeader)> {
+
+        let removed: Vec<(Hash, BlockHeader)> = self.chain.drain(height..).collect();
+
+        for (hash, _) in &removed {
+
+            self.heights.remove(hash);
+        }
+
+        removed
+    }
+}
+
+
+impl Saveable for ChainState {
+
+
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+
+
+        let mut state: ChainState = ciborium::de::from_reader(reader).map_err(|_| {
+
+            IoError::new(IoErrorKind::InvalidData,
+             "failed to deserialize the data"
+            )
+        })?;
+
+        state.heights = state.chain.iter()
+            .enumerate()
+            .map(|(height, (hash, _))| (*hash, height))
+            .collect();
+
+        Ok(state)
+    }
+
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+
+            IoError::new(IoErrorKind::InvalidData,
+             "failed to serialize the data"
+            )
+        }) 
+    }
+
+
+
+}
+
+
+impl Saveable for SavedMempool {
+
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+
+        ciborium::de::from_reader(reader).map_err(|_| {
+
+            IoError::new(IoErrorKind::InvalidData, "failed to deserialize the mempool")
+        })
+    }
+
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+
+            IoError::new(IoErrorKind::InvalidData, "failed to serialize the mempool")
+        })
+    }
+}
+
+
+// the target for the next difficulty period, from the one of the period that just ended
+// `first` and `last` are the first and last headers of that period
+
+fn retarget(params: &ChainParams, target: U256, first: &BlockHeader, last: &BlockHeader) -> U256 {
+
+    let time_diff = last.timestamp - first.timestamp;
+
+    let target_seconds = params.ideal_block_time * params.difficulty_update_interval;
+
+    // the period counts as having lasted between a quarter and four times the ideal time, whatever
+    // the timestamps say, a side branch can carry any of them and the target must not overflow
+
+    let time_diff_seconds = time_diff.num_seconds().clamp(target_seconds as i64 / 4, target_seconds as i64 * 4);
+
+
+    // multiply the current target by the actual time divided by the ideal time
+    // Target is difficulty 
+
+    let new_target = BigDecimal::parse_bytes(target.to_string().as_bytes(), 10).expect("Bug: impossible")
+                                                * (BigDecimal::from(time_diff_seconds) / BigDecimal::from(target_seconds));
+
+    // cut off decimal point and everything after it from string representation of new target
+
+
+    let new_target_str = new_target.to_string().split('.').next().expect("expected a decimal point").to_owned();
+
+    let new_target: U256 = U256::from_str_radix(&new_target_str, 10).expect("Bug: Impossible");
+
+    // clamp new_target to be within the range of 4 * target adn target / 4 
+
+    let new_target = if new_target < target / 4 {
+
+        target / 4
+
+    }  else if new_target > target * 4 {
+         
+        target * 4
+
+    } else {
+
+        new_target
+    };
+
+    // finally, we need to ensure that we do not decrease the target below minimum target
+
+    // if the new target is more than the min-target , set it to the minimum target
+
+    let new_target = new_target.min(params.min_target);
+
+    // headers carry the target in compact form, so we round it to what they can express
+
+    U256::from_compact(new_target.to_compact()).expect("BUG: compact form of a valid target")
+}
+
+
+// whether a block failing with `error` can never become valid
+// a block from the future may be fine later on, and a storage error says nothing about the block
+
+fn is_permanent(error: &BtcError) -> bool {
+
+    match error {
+
+        BtcError::RuleViolation(violation) => violation.rule != Rule::Timestamp,
+
+        BtcError::Storage(_) => false,
+
+        _ => true,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::mempool::ReplacementRejection;
+    use crate::template::TemplateLimits;
+    use crate::types::{SigHashType, TransactionOutput};
+    use crate::util::MerkleRoot;
+    use chrono::Duration;
+
+
+    // a data directory of its own for every test, removed again when the test is done
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+
+        fn new(name: &str) -> Self {
+
+            let path = std::env::temp_dir().join(format!("btc-{}-{}", name, std::process::id()));
+
+            let _ = fs::remove_dir_all(&path);
+
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+
+        fn drop(&mut self) {
+
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+
+    // a block at `height` on top of `prev`, `tag` tells apart blocks at the same height
+    // the coinbase pays the block reward plus `extra`, anything above zero makes the block invalid
+
+    fn mine_block(params: &ChainParams, prev: Hash, height: u64, start: DateTime<Utc>, tag: u8, extra: u64) -> Block {
+
+        let value = params.block_reward(height).checked_add(Amount::from_sat(extra)).unwrap();
+
+        let transactions = vec![Transaction::new_coinbase(
+            height,
+            vec![tag],
+            vec![TransactionOutput { value, pubkey: PrivateKey::new_key().public_key() }],
+        )];
+
+        let header = BlockHeader::new(
+            start + Duration::seconds(height as i64),
+            0,
+            prev,
+            MerkleRoot::calculate(&transactions),
+            params.min_target.to_compact(),
+        );
+
+        let mut block = Block::new(header, transactions);
+
+        while !block.header.mine(1_000_000) {}
+
+        block
+    }
+
+
+    // a branch of `length` blocks on top of `prev` at `height`
+
+    fn mine_branch(params: &ChainParams, mut prev: Hash, height: u64, length: u64, start: DateTime<Utc>, tag: u8) -> Vec<Block> {
+
+        (height..height + length)
+            .map(|height| {
+
+                let block = mine_block(params, prev, height, start, tag, 0);
+
+                prev = block.hash();
+
+                block
+            })
+            .collect()
+    }
+
+
+    fn coinbase_is_unspent(blockchain: &Blockchain, block: &Block) -> bool {
+
+        blockchain.utxos().get(&block.transactions[0].outpoint(0)).unwrap().is_some()
+    }
+
+
+    // the next block on top of the tip, built from the mempool the way a miner does and paying `key`,
+    // with `extra` transactions appended
+
+    fn mine_next(blockchain: &Blockchain, key: &PrivateKey, extra: Vec<Transaction>) -> Block {
+
+        let mut block = blockchain.build_template(key.public_key(), TemplateLimits::default()).unwrap();
+
+        block.transactions.extend(extra);
+        block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
+
+        while !block.header.mine(1_000_000) {}
+
+        block
+    }
+
+
+    // mine `count` blocks paying `key` on top of the tip, then enough blocks for their coinbases to mature
+    // returns the coinbase outputs, the mempool takes transactions spending them right away
+
+    fn mine_coins(blockchain: &mut Blockchain, key: &PrivateKey, count: usize) -> Vec<(OutPoint, Amount)> {
+
+        let maturity = blockchain.params().coinbase_maturity as usize;
+
+        let mut coins = vec![];
+
+        for i in 0..count + maturity {
+
+            let block = mine_next(blockchain, key, vec![]);
+
+            if i < count {
+
+                coins.push((block.transactions[0].outpoint(0), block.transactions[0].outputs[0].value));
+            }
+
+            blockchain.add_block(block).unwrap();
+        }
+
+        coins
+    }
+
+
+    // a transaction spending `coins` into `outputs` outputs paying `key`, leaving `fee` to the miner
+
+    fn spend(coins: &[(OutPoint, Amount)], fee: u64, outputs: usize, key: &PrivateKey) -> Transaction {
+
+        let total = coins.iter().map(|(_, value)| value.to_sat()).sum::<u64>() - fee;
+
+        let value = total / outputs as u64;
+
+        let mut outputs: Vec<TransactionOutput> = (0..outputs)
+            .map(|_| TransactionOutput { value: Amount::from_sat(value), pubkey: key.public_key() })
+            .collect();
+
+        // the rounding goes to the first output
+
+        outputs[0].value = Amount::from_sat(total - value * (outputs.len() as u64 - 1));
+
+        let spends: Vec<(OutPoint, &PrivateKey)> = coins.iter().map(|(outpoint, _)| (*outpoint, key)).collect();
+
+        Transaction::new_signed(&spends, outputs, SigHashType::ALL).unwrap()
+    }
+
+
+    #[test]
+    fn reorganizes_to_the_branch_with_more_work() {
+
+        let dir = TempDir::new("reorg");
+        let params = ChainParams::regtest();
+        let start = Utc::now();
+
+        let mut blockchain = Blockchain::open(&dir.0, params.clone()).unwrap();
+
+        let genesis = blockchain.tip_hash();
+
+        let active = mine_branch(&params, genesis, 1, 2, start, 1);
+        let side = mine_branch(&params, genesis, 1, 3, start, 2);
+
+        for block in &active {
+
+            blockchain.add_block(block.clone()).unwrap();
+        }
+
+        // as much work as the active chain is not enough, the chain we saw first wins
+
+        blockchain.add_block(side[0].clone()).unwrap();
+        blockchain.add_block(side[1].clone()).unwrap();
+
+        assert_eq!(blockchain.tip_hash(), active[1].hash());
+
+        blockchain.add_block(side[2].clone()).unwrap();
+
+        assert_eq!(blockchain.tip_hash(), side[2].hash());
+        assert_eq!(blockchain.blocks_height(), 4);
+
+        assert!(active.iter().all(|block| !coinbase_is_unspent(&blockchain, block)));
+        assert!(side.iter().all(|block| coinbase_is_unspent(&blockchain, block)));
+
+        // the old branch is kept, and can win again
+
+        assert!(blockchain.contains_block(&active[1].hash()));
+
+        // the same state is there after a restart
+
+        blockchain.flush().unwrap();
+        drop(blockchain);
+
+        let blockchain = Blockchain::open(&dir.0, params).unwrap();
+
+        assert_eq!(blockchain.tip_hash(), side[2].hash());
+        assert_eq!(blockchain.utxos().tip(), side[2].hash());
+        assert!(side.iter().all(|block| coinbase_is_unspent(&blockchain, block)));
+    }
+
+
+    #[test]
+    fn disconnect_tip_restores_the_utxo_set() {
+
+        let dir = TempDir::new("disconnect");
+        let params = ChainParams::regtest();
+        let start = Utc::now();
+
+        let mut blockchain = Blockchain::open(&dir.0, params.clone()).unwrap();
+
+        let blocks = mine_branch(&params, blockchain.tip_hash(), 1, 2, start, 1);
+
+        for block in &blocks {
+
+            blockchain.add_block(block.clone()).unwrap();
+        }
+
+        let disconnected = blockchain.disconnect_tip().unwrap().unwrap();
+
+        assert_eq!(disconnected.hash(), blocks[1].hash());
+        assert_eq!(blockchain.tip_hash(), blocks[0].hash());
+        assert_eq!(blockchain.utxos().tip(), blocks[0].hash());
+
+        assert!(coinbase_is_unspent(&blockchain, &blocks[0]));
+        assert!(!coinbase_is_unspent(&blockchain, &blocks[1]));
+
+        // the block applies again on top of the new tip
+
+        blockchain.add_block(disconnected).unwrap();
+
+        assert_eq!(blockchain.tip_hash(), blocks[1].hash());
+        assert!(coinbase_is_unspent(&blockchain, &blocks[1]));
+    }
+
+
+    #[test]
+    fn rolls_back_when_the_new_branch_is_invalid() {
+
+        let dir = TempDir::new("rollback");
+        let params = ChainParams::regtest();
+        let start = Utc::now();
+
+        let mut blockchain = Blockchain::open(&dir.0, params.clone()).unwrap();
+
+        let genesis = blockchain.tip_hash();
+
+        let active = mine_branch(&params, genesis, 1, 2, start, 1);
+
+        for block in &active {
+
+            blockchain.add_block(block.clone()).unwrap();
+        }
+
+        // a heavier branch whose last block pays itself too much
+
+        let mut side = mine_branch(&params, genesis, 1, 2, start, 2);
+
+        side.push(mine_block(&params, side[1].hash(), 3, start, 2, 1));
+
+        let child = mine_block(&params, side[2].hash(), 4, start, 2, 0);
+
+        blockchain.add_block(side[0].clone()).unwrap();
+        blockchain.add_block(side[1].clone()).unwrap();
+
+        assert!(blockchain.add_block(side[2].clone()).is_err());
+
+        assert_eq!(blockchain.tip_hash(), active[1].hash());
+        assert_eq!(blockchain.blocks_height(), 3);
+        assert_eq!(blockchain.utxos().tip(), active[1].hash());
+
+        assert!(active.iter().all(|block| coinbase_is_unspent(&blockchain, block)));
+        assert!(side.iter().all(|block| !coinbase_is_unspent(&blockchain, block)));
+
+        // the valid part of the branch stays around, the invalid block and what builds on it is refused
+
+        assert!(blockchain.contains_block(&side[1].hash()));
+        assert!(!blockchain.contains_block(&side[2].hash()));
+
+        assert!(blockchain.add_block(side[2].clone()).is_err());
+        assert!(blockchain.add_block(child).is_err());
+
+        // and the active chain can still be extended
+
+        let next = mine_block(&params, active[1].hash(), 3, start, 1, 0);
+
+        blockchain.add_block(next.clone()).unwrap();
+
+        assert_eq!(blockchain.tip_hash(), next.hash());
+    }
+
+
+    #[test]
+    fn remembers_invalid_blocks_only_after_checking_their_work() {
+
+        let dir = TempDir::new("invalid");
+        let params = ChainParams::regtest();
+        let start = Utc::now();
+
+        let mut blockchain = Blockchain::open(&dir.0, params.clone()).unwrap();
+
+        let genesis = blockchain.tip_hash();
+
+        let active = mine_block(&params, genesis, 1, start, 1, 0);
+
+        blockchain.add_block(active).unwrap();
+
+        let valid = mine_block(&params, genesis, 1, start, 2, 0);
+        let invalid = mine_block(&params, valid.hash(), 2, start, 2, 1);
+
+        blockchain.add_block(valid).unwrap();
+
+        assert!(blockchain.add_block(invalid.clone()).is_err());
+        assert_eq!(blockchain.state.invalid_blocks, VecDeque::from([invalid.hash()]));
+
+        // blocks on the invalid one are refused, with or without the work, and none of them is remembered
+
+        for nonce in 0..10 {
+
+            let mut block = mine_block(&params, invalid.hash(), 3, start, nonce, 0);
+
+            if nonce % 2 == 0 {
+
+                block.header.bits = 0;
+            }
+
+            assert!(blockchain.add_block(block).is_err());
+        }
+
+        assert_eq!(blockchain.state.invalid_blocks.len(), 1);
+
+        // and there is a limit to how many invalid blocks are remembered
+
+        blockchain.state.invalid_blocks.extend((0..MAX_INVALID_BLOCKS as u64).map(|n| Hash::hash(&n)));
+
+        blockchain.discard_side_blocks(Hash::hash(&"another invalid block"), true);
+
+        assert_eq!(blockchain.state.invalid_blocks.len(), MAX_INVALID_BLOCKS);
+        assert!(!blockchain.state.invalid_blocks.contains(&invalid.hash()));
+    }
+
+
+    #[test]
+    fn recovers_from_a_storage_error_during_a_reorganization() {
+
+        let dir = TempDir::new("recover");
+        let params = ChainParams::regtest();
+        let start = Utc::now();
+
+        let mut blockchain = Blockchain::open(&dir.0, params.clone()).unwrap();
+
+        let genesis = blockchain.tip_hash();
+
+        let active = mine_branch(&params, genesis, 1, 2, start, 1);
+        let side = mine_branch(&params, genesis, 1, 4, start, 2);
+
+        for block in active.iter().chain(&side[..2]) {
+
+            blockchain.add_block(block.clone()).unwrap();
+        }
+
+        // the blocks can not be read while the reorganization runs
+
+        let blocks = dir.0.join(BLOCKS_DIR).join("blk00000.dat");
+        let moved = dir.0.join("blk00000.dat");
+
+        fs::rename(&blocks, &moved).unwrap();
+
+        assert!(matches!(blockchain.add_block(side[2].clone()), Err(BtcError::Storage(_))));
+
+        // the old chain is still the active one, and the UTXO set is rebuilt once the storage is back
+
+        assert_eq!(blockchain.tip_hash(), active[1].hash());
+        assert!(blockchain.state.utxos_stale);
+
+        fs::rename(&moved, &blocks).unwrap();
+
+        let next = mine_block(&params, active[1].hash(), 3, start, 1, 0);
+
+        blockchain.add_block(next.clone()).unwrap();
+
+        assert!(!blockchain.state.utxos_stale);
+        assert_eq!(blockchain.tip_hash(), next.hash());
+        assert!(active.iter().chain([&next]).all(|block| coinbase_is_unspent(&blockchain, block)));
+        assert!(side.iter().all(|block| !coinbase_is_unspent(&blockchain, block)));
+
+        // and the other branch can still take over
+
+        blockchain.add_block(side[3].clone()).unwrap();
+
+        assert_eq!(blockchain.tip_hash(), side[3].hash());
+        assert!(side.iter().all(|block| coinbase_is_unspent(&blockchain, block)));
+    }
+
+
+    #[test]
+    fn keeps_the_replaced_transactions_when_the_replacement_does_not_fit() {
+
+        let dir = TempDir::new("replace-full");
+        let mut params = ChainParams::regtest();
+        let key = PrivateKey::new_key();
+
+        let mut blockchain = Blockchain::open(&dir.0, params.clone()).unwrap();
+
+        let coins = mine_coins(&mut blockchain, &key, 2);
+
+        // a cheap parent and child, and an expensive transaction that fill the mempool exactly
+
+        let parent = spend(&coins[..1], 500, 1, &key);
+        let child = spend(&[(parent.outpoint(0), parent.outputs[0].value)], 500, 1, &key);
+        let expensive = spend(&coins[1..], 1_000_000, 1, &key);
+
+        // pays enough to replace both, but its size pushes the mempool over, and its fee rate is the lowest
+
+        let replacement = spend(&coins[..1], 20_000, 40, &key);
+
+        assert!(replacement.serialized_size() > parent.serialized_size() + child.serialized_size());
+
+        params.max_mempool_size = parent.serialized_size() + child.serialized_size() + expensive.serialized_size();
+
+        blockchain.flush().unwrap();
+        drop(blockchain);
+
+        let mut blockchain = Blockchain::open(&dir.0, params).unwrap();
+
+        for transaction in [&parent, &child, &expensive] {
+
+            blockchain.add_to_mempool(transaction.clone()).unwrap();
+        }
+
+        let error = blockchain.add_to_mempool(replacement.clone()).unwrap_err();
+
+        assert!(error.to_string().contains("mempool is full"), "{}", error);
+
+        assert_eq!(blockchain.mempool().len(), 3);
+        assert!([&parent, &child, &expensive].iter().all(|transaction| blockchain.mempool().contains(&transaction.hash())));
+        assert!(!blockchain.mempool().contains(&replacement.hash()));
+
+        // what they spend is still taken
+
+        assert!(blockchain.utxos().get(&coins[0].0).unwrap().unwrap().0);
+        assert!(blockchain.utxos().get(&coins[1].0).unwrap().unwrap().0);
+    }
+
+
+    #[test]
+    fn replaces_a_transaction_and_its_descendants() {
+
+        let dir = TempDir::new("replace");
+        let key = PrivateKey::new_key();
+
+        let mut blockchain = Blockchain::open(&dir.0, ChainParams::regtest()).unwrap();
+
+        let coins = mine_coins(&mut blockchain, &key, 1);
+
+        let original = spend(&coins, 1_000, 1, &key);
+        let child = spend(&[(original.outpoint(0), original.outputs[0].value)], 1_000, 1, &key);
+
+        blockchain.add_to_mempool(original.clone()).unwrap();
+        blockchain.add_to_mempool(child.clone()).unwrap();
+
+        // the same fee in a larger transaction is not enough
+
+        let error = blockchain.add_to_mempool(spend(&coins, 1_000, 2, &key)).unwrap_err();
+
+        assert!(matches!(error, BtcError::ReplacementRejected(ReplacementRejection::FeeRateTooLow { .. })), "{}", error);
+
+        let replacement = spend(&coins, 10_000, 1, &key);
+
+        blockchain.add_to_mempool(replacement.clone()).unwrap();
+
+        assert_eq!(blockchain.mempool().len(), 1);
+        assert!(blockchain.mempool().contains(&replacement.hash()));
+
+        // the coin is spent by the replacement now
+
+        assert!(blockchain.utxos().get(&coins[0].0).unwrap().unwrap().0);
+        assert_eq!(blockchain.mempool().spender(&coins[0].0), Some(replacement.hash()));
+    }
+
+
+    #[test]
+    fn rejects_blocks_outside_the_allowed_time_range() {
+
+        let dir = TempDir::new("timestamps");
+        let params = ChainParams::regtest();
+        let key = PrivateKey::new_key();
+
+        let mut blockchain = Blockchain::open(&dir.0, params.clone()).unwrap();
+
+        let genesis = blockchain.tip_hash();
+
+        for block in mine_branch(&params, genesis, 1, 12, Utc::now() - Duration::hours(1), 1) {
+
+            blockchain.add_block(block).unwrap();
+        }
+
+        let median = validation::median_time_past(blockchain.headers()).unwrap();
+
+        let block_at = |blockchain: &Blockchain, timestamp: DateTime<Utc>| {
+
+            let mut block = blockchain.build_template(key.public_key(), TemplateLimits::default()).unwrap();
+
+            block.header.timestamp = timestamp;
+
+            while !block.header.mine(1_000_000) {}
+
+            block
+        };
+
+        let error = blockchain.add_block(block_at(&blockchain, median)).unwrap_err();
+
+        assert!(error.to_string().contains("not after the median time"), "{}", error);
+
+        let future = Utc::now() + Duration::seconds(params.max_future_block_time as i64) + Duration::minutes(1);
+
+        let error = blockchain.add_block(block_at(&blockchain, future)).unwrap_err();
+
+        assert!(error.to_string().contains("too far in the future"), "{}", error);
+
+        blockchain.add_block(block_at(&blockchain, median + Duration::seconds(1))).unwrap();
+
+        assert_eq!(blockchain.blocks_height(), 14);
+    }
+
+
+    #[test]
+    fn spends_a_coinbase_only_once_it_matured() {
+
+        let dir = TempDir::new("maturity");
+        let params = ChainParams::regtest();
+        let key = PrivateKey::new_key();
+
+        let mut blockchain = Blockchain::open(&dir.0, params.clone()).unwrap();
+
+        let block = mine_next(&blockchain, &key, vec![]);
+        let coin = (block.transactions[0].outpoint(0), block.transactions[0].outputs[0].value);
+
+        blockchain.add_block(block).unwrap();
+
+        // the coinbase is at height 1, the next block is one short of maturity
+
+        while blockchain.blocks_height() < params.coinbase_maturity {
+
+            let block = mine_next(&blockchain, &key, vec![]);
+
+            blockchain.add_block(block).unwrap();
+        }
+
+        let error = blockchain.add_to_mempool(spend(&[coin], 1_000, 1, &key)).unwrap_err();
+
+        assert!(error.to_string().contains("coinbase output is not mature yet"), "{}", error);
+
+        let block = mine_next(&blockchain, &key, vec![spend(&[coin], 0, 1, &key)]);
+
+        let error = blockchain.add_block(block).unwrap_err();
+
+        assert!(error.to_string().contains("coinbase output is not mature yet"), "{}", error);
+
+        // one block later it can be spent, in the mempool and in a block
+
+        let block = mine_next(&blockchain, &key, vec![]);
+
+        blockchain.add_block(block).unwrap();
+
+        let spending = spend(&[coin], 1_000, 1, &key);
+
+        blockchain.add_to_mempool(spending.clone()).unwrap();
+
+        let block = mine_next(&blockchain, &key, vec![]);
+
+        assert!(block.transactions.iter().any(|transaction| transaction.hash() == spending.hash()));
+
+        blockchain.add_block(block).unwrap();
+
+        assert!(blockchain.utxos().get(&coin.0).unwrap().is_none());
+        assert!(blockchain.mempool().is_empty());
+    }
+
+
+    #[test]
+    fn reloads_the_mempool_without_expired_or_invalid_transactions() {
+
+        let dir = TempDir::new("mempool-reload");
+        let params = ChainParams::regtest();
+        let key = PrivateKey::new_key();
+
+        let mut blockchain = Blockchain::open(&dir.0, params.clone()).unwrap();
+
+        let coins = mine_coins(&mut blockchain, &key, 3);
+
+        let parent = spend(&coins[0..1], 1_000, 1, &key);
+        let child = spend(&[(parent.outpoint(0), parent.outputs[0].value)], 1_000, 1, &key);
+        let expired = spend(&coins[1..2], 1_000, 1, &key);
+        let invalid = spend(&coins[2..3], 1_000, 1, &key);
+
+        blockchain.add_to_mempool(parent.clone()).unwrap();
+        blockchain.add_to_mempool(child.clone()).unwrap();
+        blockchain.add_to_mempool(invalid.clone()).unwrap();
+
+        let max_age = chrono::Duration::seconds(params.max_mempool_transaction_age as i64);
+
+        blockchain.accept_to_mempool(expired.clone(), Utc::now() - max_age - Duration::minutes(1)).unwrap();
+
+        blockchain.save_mempool().unwrap();
+        blockchain.flush().unwrap();
+        drop(blockchain);
+
+        // while the saved mempool is put aside, a block spends what `invalid` spends
+
+        let saved = dir.0.join(MEMPOOL_FILE);
+        let aside = saved.with_extension("aside");
+
+        fs::rename(&saved, &aside).unwrap();
+
+        let mut blockchain = Blockchain::open(&dir.0, params.clone()).unwrap();
+
+        assert!(blockchain.mempool().is_empty());
+
+        let block = mine_next(&blockchain, &key, vec![spend(&coins[2..3], 0, 2, &key)]);
+
+        blockchain.add_block(block).unwrap();
+        blockchain.flush().unwrap();
+        drop(blockchain);
+
+        fs::rename(&aside, &saved).unwrap();
+
+        let blockchain = Blockchain::open(&dir.0, params).unwrap();
+
+        // the parent comes back before its child, the other two are gone
+
+        assert_eq!(blockchain.mempool().len(), 2);
+        assert!(blockchain.mempool().contains(&parent.hash()));
+        assert!(blockchain.mempool().contains(&child.hash()));
+
+        // and only what the mempool spends is marked again
+
+        assert!(blockchain.utxos().get(&coins[0].0).unwrap().unwrap().0);
+        assert!(!blockchain.utxos().get(&coins[1].0).unwrap().unwrap().0);
+        assert!(blockchain.utxos().get(&coins[2].0).unwrap().is_none());
+    }
+
+
+    #[test]
+    fn mines_templates_with_unconfirmed_parents() {
+
+        let dir = TempDir::new("template");
+        let key = PrivateKey::new_key();
+
+        let mut blockchain = Blockchain::open(&dir.0, ChainParams::regtest()).unwrap();
+
+        let coins = mine_coins(&mut blockchain, &key, 2);
+
+        // a cheap parent with a child paying for both, another cheap child, and an unrelated transaction
+
+        let parent = spend(&coins[0..1], 500, 2, &key);
+        let rich_child = spend(&[(parent.outpoint(0), parent.outputs[0].value)], 50_000, 1, &key);
+        let poor_child = spend(&[(parent.outpoint(1), parent.outputs[1].value)], 500, 1, &key);
+        let unrelated = spend(&coins[1..2], 5_000, 1, &key);
+
+        for transaction in [&parent, &rich_child, &poor_child, &unrelated] {
+
+            blockchain.add_to_mempool(transaction.clone()).unwrap();
+        }
+
+        // with room for a single transaction, the best package does not fit, but the unrelated one does
+
+        let limits = TemplateLimits { max_transactions: Some(1), ..TemplateLimits::default() };
+
+        let mut block = blockchain.build_template(key.public_key(), limits).unwrap();
+
+        while !block.header.mine(1_000_000) {}
+
+        assert_eq!(block.transactions.len(), 2);
+        assert_eq!(block.transactions[1].hash(), unrelated.hash());
+
+        blockchain.add_block(block).unwrap();
+
+        // without a cap everything goes in, every parent before its children
+
+        let block = mine_next(&blockchain, &key, vec![]);
+
+        let position = |transaction: &Transaction| {
+
+            block.transactions.iter().position(|included| included.hash() == transaction.hash()).unwrap()
+        };
+
+        assert_eq!(block.transactions.len(), 4);
+        assert!(position(&parent) < position(&rich_child));
+        assert!(position(&parent) < position(&poor_child));
+
+        // the package of the parent and its rich child comes first
+
+        assert_eq!(position(&parent), 1);
+
+        blockchain.add_block(block).unwrap();
+
+        assert!(blockchain.mempool().is_empty());
+        assert_eq!(blockchain.utxos().get(&rich_child.outpoint(0)).unwrap().unwrap().1.output.value, rich_child.outputs[0].value);
+    }
+}
@@ -1,8 +1,13 @@
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
-use crate::crypto::{PublicKey, Signature};
+use crate::amount::Amount;
+use crate::crypto::{PrivateKey, Signature};
+use crate::error::Result;
+use crate::params::ChainParams;
 use crate::sha256::Hash;
-use crate::util::Saveable;
+use crate::util::{ByteCounter, Saveable};
+use crate::utxo::UtxoView;
+use crate::validation::{self, ValidationLevel};
+use std::fmt;
 use std::io::{
     Error as IoError, ErrorKind as IoErrorKind, Read,
     Result as IoResult, Write,
@@ -18,6 +23,8 @@ pub struct Transaction {
     pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>,
 
+    // only set on the coinbase, which takes the place of its inputs
+    pub coinbase: Option<CoinbaseInput>,
 }
 
 impl Transaction {
@@ -27,13 +34,111 @@ impl Transaction {
         Transaction{
             inputs: inputs,
             outputs: outputs,
+            coinbase: None,
         }
     }
 
+
+    // the first transaction of the block at `height`, it creates the block reward out of nothing
+
+    pub fn new_coinbase(height: u64, extra_nonce: Vec<u8>, outputs: Vec<TransactionOutput>) -> Self {
+
+        Transaction {
+            inputs: vec![],
+            outputs,
+            coinbase: Some(CoinbaseInput { height, extra_nonce }),
+        }
+    }
+
+
+    // the size of the transaction in bytes, the way it is stored and sent to other nodes
+
+    pub fn serialized_size(&self) -> usize {
+
+        let mut counter = ByteCounter::default();
+
+        self.save(&mut counter).expect("BUG: a transaction can always be serialized");
+
+        counter.0
+    }
+
+
+    pub fn is_coinbase(&self) -> bool {
+
+        self.coinbase.is_some()
+    }
+
+
+    // check the transaction against the unspent outputs in `utxos`, for inclusion in a block at `height`
+    // see validation::validate_transaction(), returns the fee it pays
+
+    pub fn validate<V: UtxoView>(&self, utxos: &V, height: u64, params: &ChainParams, level: ValidationLevel) -> Result<Amount> {
+
+        validation::validate_transaction(self, utxos, height, params, level)
+    }
+
+
+    // the fee the transaction pays, its inputs have to be unspent outputs in `utxos`
+
+    pub fn fee<V: UtxoView>(&self, utxos: &V) -> Result<Amount> {
+
+        validation::transaction_fee(self, utxos)
+    }
+
+
+    // the hash the signature of input `input` signs, None if `sighash` can not be used for that input
+
+    pub fn signature_hash(&self, input: usize, sighash: SigHashType) -> Option<Hash> {
+
+        let spends: Vec<OutPoint> = self.inputs
+            .iter()
+            .map(|input| input.previous_output)
+            .collect();
+
+        signature_hash(&spends, &self.outputs, input, sighash)
+    }
+
+
+    // build a transaction spending the outputs at `spends`, paying to `outputs`,
+    // and sign every input with its key
+
+    pub fn new_signed(spends: &[(OutPoint, &PrivateKey)], outputs: Vec<TransactionOutput>, sighash: SigHashType) -> Option<Self> {
+
+        let outpoints: Vec<OutPoint> = spends.iter().map(|(outpoint, _)| *outpoint).collect();
+
+        let mut inputs = vec![];
+
+        for (idx, (outpoint, private_key)) in spends.iter().enumerate() {
+
+            let signature_hash = signature_hash(&outpoints, &outputs, idx, sighash)?;
+
+            inputs.push(TransactionInput {
+                previous_output: *outpoint,
+                signature: Signature::sign_hash(&signature_hash, private_key),
+                sighash,
+            });
+        }
+
+        Some(Transaction::new(inputs, outputs))
+    }
+
+    // the transaction id, outputs are referred to by it and their index
+
     pub fn hash(&self) -> Hash{
 
         Hash::hash(self)
     }
+
+
+    // where the output at `vout` of this transaction can be found
+
+    pub fn outpoint(&self, vout: usize) -> OutPoint {
+
+        OutPoint {
+            txid: self.hash(),
+            vout: vout as u32,
+        }
+    }
 }
 
 
@@ -69,30 +174,337 @@ impl Saveable for Transaction {
 
 
 
+// what a coinbase has instead of inputs
+// committing to the height makes every coinbase unique, even if it pays the same key the same amount,
+// and the extra nonce is free for the miner to change, which changes the merkle root
+// and gives it a fresh range of header nonces to try
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CoinbaseInput {
+
+    // height of the block the coinbase is in
+    pub height: u64,
+
+    pub extra_nonce: Vec<u8>,
+}
+
+
+// a reference to an output: the transaction that created it, and its position among that transaction's outputs
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+
+    pub txid: Hash,
+
+    pub vout: u32,
+}
+
+impl fmt::Display for OutPoint {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+        write!(f, "{}:{}", self.txid, self.vout)
+    }
+}
+
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionInput{
 
-    pub prev_transaction_output_hash: Hash,
+    // the output this input spends
+    pub previous_output: OutPoint,
 
     // @note replacing the script with simple signature field to make it simpler
     pub signature: crate::crypto::Signature,
+
+    // which parts of the transaction the signature covers
+    pub sighash: SigHashType,
+}
+
+
+// Signature hash types, the same ones bitcoin has.
+// A signature always covers the input it is for, the mode picks the outputs it covers:
+//
+//   All     every output, nobody can change where the money goes
+//   None    no output, whoever completes the transaction decides
+//   Single  only the output at the same position as the input
+//
+// and unless anyone_can_pay is set, it also covers every other input,
+// so nobody can add or remove inputs without invalidating it.
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SigHashMode {
+
+    All,
+
+    None,
+
+    Single,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SigHashType {
+
+    pub mode: SigHashMode,
+
+    pub anyone_can_pay: bool,
+}
+
+impl SigHashType {
+
+    pub const ALL: Self = SigHashType { mode: SigHashMode::All, anyone_can_pay: false };
+
+    pub const NONE: Self = SigHashType { mode: SigHashMode::None, anyone_can_pay: false };
+
+    pub const SINGLE: Self = SigHashType { mode: SigHashMode::Single, anyone_can_pay: false };
+
+
+    // the same mode, covering only the signed input
+
+    pub fn anyone_can_pay(self) -> Self {
+
+        SigHashType { anyone_can_pay: true, ..self }
+    }
+}
+
+
+// what a signature actually signs, hashed
+
+#[derive(Serialize)]
+struct SigHashPreimage<'a> {
+
+    sighash: SigHashType,
+
+    // the outputs the transaction spends, with anyone_can_pay only the one of the signed input
+    spends: Vec<&'a OutPoint>,
+
+    // position of the signed input, left out with anyone_can_pay so other inputs can be added in front of it,
+    // unless Single ties the input to the output at its position
+    position: Option<usize>,
+
+    outputs: Vec<&'a TransactionOutput>,
+}
+
+
+// the hash the signature of input `input` signs, for a transaction spending the outputs at `spends`
+// and paying to `outputs`
+// None if there is no such input, or if Single is used on an input without an output at its position
+
+pub fn signature_hash(spends: &[OutPoint], outputs: &[TransactionOutput], input: usize, sighash: SigHashType) -> Option<Hash> {
+
+    let spent = spends.get(input)?;
+
+    let spends = if sighash.anyone_can_pay {
+
+        vec![spent]
+
+    } else {
+
+        spends.iter().collect()
+    };
+
+    let position = (!sighash.anyone_can_pay || sighash.mode == SigHashMode::Single).then_some(input);
+
+    let outputs = match sighash.mode {
+
+        SigHashMode::All => outputs.iter().collect(),
+
+        SigHashMode::None => vec![],
+
+        SigHashMode::Single => vec![outputs.get(input)?],
+    };
+
+    Some(Hash::hash(&SigHashPreimage { sighash, spends, position, outputs }))
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionOutput{
 
-    pub value: u64,
-    pub unique_id: Uuid,
+    pub value: Amount,
     pub pubkey: crate::crypto::PublicKey,
 
 
 }
 
-impl TransactionOutput {
 
-    pub fn hash(&self) -> Hash {
-    Hash::hash(self)
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::crypto::PrivateKey;
 
+
+    fn spends(count: u32) -> Vec<OutPoint> {
+
+        (0..count).map(|vout| OutPoint { txid: Hash::hash(&vout), vout }).collect()
     }
-}    
 
+
+    fn outputs(values: &[u64]) -> Vec<TransactionOutput> {
+
+        let pubkey = PrivateKey::new_key().public_key();
+
+        values
+            .iter()
+            .map(|value| TransactionOutput { value: Amount::from_sat(*value), pubkey: pubkey.clone() })
+            .collect()
+    }
+
+
+    #[test]
+    fn all_covers_every_input_and_output() {
+
+        let spends = spends(3);
+        let outputs = outputs(&[10, 20]);
+
+        let hash = signature_hash(&spends, &outputs, 1, SigHashType::ALL);
+
+        let mut changed = outputs.clone();
+        changed[0].value = Amount::from_sat(11);
+
+        assert_ne!(signature_hash(&spends, &changed, 1, SigHashType::ALL), hash);
+
+        assert_ne!(signature_hash(&spends, &outputs[..1], 1, SigHashType::ALL), hash);
+
+        assert_ne!(signature_hash(&spends[..2], &outputs, 1, SigHashType::ALL), hash);
+
+        // the same inputs in another order
+
+        let swapped = vec![spends[2], spends[1], spends[0]];
+
+        assert_ne!(signature_hash(&swapped, &outputs, 1, SigHashType::ALL), hash);
+    }
+
+
+    #[test]
+    fn none_covers_the_inputs_but_no_output() {
+
+        let spends = spends(3);
+        let outputs = outputs(&[10, 20]);
+
+        let hash = signature_hash(&spends, &outputs, 1, SigHashType::NONE);
+
+        assert_eq!(signature_hash(&spends, &outputs[..1], 1, SigHashType::NONE), hash);
+        assert_eq!(signature_hash(&spends, &[], 1, SigHashType::NONE), hash);
+
+        assert_ne!(signature_hash(&spends[..2], &outputs, 1, SigHashType::NONE), hash);
+    }
+
+
+    #[test]
+    fn single_covers_the_output_at_the_position_of_its_input() {
+
+        let spends = spends(3);
+        let outputs = outputs(&[10, 20]);
+
+        let hash = signature_hash(&spends, &outputs, 1, SigHashType::SINGLE);
+
+        let mut changed = outputs.clone();
+        changed[0].value = Amount::from_sat(11);
+
+        assert_eq!(signature_hash(&spends, &changed, 1, SigHashType::SINGLE), hash);
+
+        changed[1].value = Amount::from_sat(21);
+
+        assert_ne!(signature_hash(&spends, &changed, 1, SigHashType::SINGLE), hash);
+
+        // the other inputs are still covered
+
+        assert_ne!(signature_hash(&spends[..2], &outputs, 1, SigHashType::SINGLE), hash);
+
+        // there is no output at the position of the third input
+
+        assert_eq!(signature_hash(&spends, &outputs, 2, SigHashType::SINGLE), None);
+    }
+
+
+    #[test]
+    fn anyone_can_pay_covers_only_the_signed_input() {
+
+        let spends = spends(3);
+        let outputs = outputs(&[10, 20]);
+
+        for sighash in [SigHashType::ALL, SigHashType::NONE] {
+
+            let sighash = sighash.anyone_can_pay();
+
+            let hash = signature_hash(&spends, &outputs, 1, sighash);
+
+            assert_eq!(signature_hash(&spends[1..2], &outputs, 0, sighash), hash);
+
+            let mut replaced = spends.clone();
+            replaced[0].vout = 7;
+
+            assert_eq!(signature_hash(&replaced, &outputs, 1, sighash), hash);
+        }
+
+        // with Single the input stays tied to the position of its output
+
+        let sighash = SigHashType::SINGLE.anyone_can_pay();
+
+        let hash = signature_hash(&spends, &outputs, 1, sighash);
+
+        let mut replaced = spends.clone();
+        replaced[0].vout = 7;
+
+        assert_eq!(signature_hash(&replaced, &outputs, 1, sighash), hash);
+
+        assert_ne!(signature_hash(&spends[1..2], &outputs[1..], 0, sighash), hash);
+    }
+
+
+    #[test]
+    fn signature_hash_depends_on_the_mode() {
+
+        let spends = spends(2);
+        let outputs = outputs(&[10, 20]);
+
+        let hashes: Vec<Option<Hash>> = [
+            SigHashType::ALL,
+            SigHashType::NONE,
+            SigHashType::SINGLE,
+            SigHashType::ALL.anyone_can_pay(),
+            SigHashType::NONE.anyone_can_pay(),
+            SigHashType::SINGLE.anyone_can_pay(),
+        ]
+        .into_iter()
+        .map(|sighash| signature_hash(&spends, &outputs, 0, sighash))
+        .collect();
+
+        for (idx, hash) in hashes.iter().enumerate() {
+
+            assert!(hash.is_some());
+
+            assert!(!hashes[..idx].contains(hash));
+        }
+
+        assert_eq!(signature_hash(&spends, &outputs, 2, SigHashType::ALL), None);
+    }
+
+
+    #[test]
+    fn signed_transaction_commits_to_its_signature_hash() {
+
+        let key = PrivateKey::new_key();
+
+        let spends = spends(2);
+
+        let transaction = Transaction::new_signed(
+            &[(spends[0], &key), (spends[1], &key)],
+            outputs(&[10]),
+            SigHashType::NONE,
+        )
+        .expect("every input has a signature hash");
+
+        for (idx, input) in transaction.inputs.iter().enumerate() {
+
+            let hash = transaction.signature_hash(idx, input.sighash).unwrap();
+
+            assert!(input.signature.verify(&hash, &key.public_key()));
+        }
+
+        // Single needs an output for every input
+
+        assert!(Transaction::new_signed(&[(spends[0], &key), (spends[1], &key)], outputs(&[10]), SigHashType::SINGLE).is_none());
+    }
+}
@@ -1,8 +1,8 @@
 use serde::{Deserialize, Serialize};
 
-use std::io:: {Read, Write, Result as IoResult};
+use std::io:: {BufWriter, Read, Write, Result as IoResult};
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::path::Path;
 
 use crate::sha256::Hash;
@@ -29,14 +29,39 @@ pub trait Saveable
     //can use anything convertible into a path, which includes a &str string slice
 
     fn save_to_file<P: AsRef<Path>>(&self, path: P) -> IoResult<()> {
-        
+
+        let path = path.as_ref();
+
+        // we never write to the target directly: the data goes to a temporary file next to it,
+        // which replaces the target only once it is completely on disk
+        // a crash in the middle of a save therefore leaves the previous file untouched
+
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
 
         //This creates a new file at the given path. 
         //If the file cannot be created (e.g., due to permissions or non-existent directories), 
         //the function returns an error (? is the "try" operator which propagates errors).
-        let file = File::create(&path)?;   
+        let mut file = File::create(&temp_path)?;
+
+        let mut writer = BufWriter::new(&mut file);
+        self.save(&mut writer)?;
+        writer.flush()?;
+        drop(writer);
+
+        file.sync_all()?;
+
+        // rename is atomic, a reader sees either the old or the new file
+        fs::rename(&temp_path, path)?;
 
-        self.save(file)
+        // make the rename itself durable
+        #[cfg(unix)]
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+
+            File::open(dir)?.sync_all()?;
+        }
+
+        Ok(())
     }
 
     fn load_from_file<P: AsRef<Path>>(path: P) -> IoResult<Self> {
@@ -49,6 +74,29 @@ pub trait Saveable
 }
 
 
+// a writer that only counts what is written to it, to find out how large something is
+// once serialized without keeping the bytes around
+
+#[derive(Debug, Default)]
+pub(crate) struct ByteCounter(pub usize);
+
+impl Write for ByteCounter {
+
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+
+        self.0 += buf.len();
+
+        Ok(buf.len())
+    }
+
+
+    fn flush(&mut self) -> IoResult<()> {
+
+        Ok(())
+    }
+}
+
+
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub struct MerkleRoot(Hash);
 
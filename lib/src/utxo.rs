@@ -0,0 +1,440 @@
+// The UTXO set, stored in an on-disk database so it does not have to fit in memory.
+// Unspent outputs are keyed by their outpoint, the id of the transaction that created them and their index.
+// Changes made while connecting and disconnecting blocks are collected in a write-back cache
+// and written out in a single database transaction, together with the hash of the block
+// the set corresponds to. After a crash the database is therefore always at some block,
+// never half way through one.
+
+use redb::{Database, ReadableTable, TableDefinition};
+use serde::{Deserialize, Serialize};
+
+use crate::sha256::Hash;
+use crate::types::{OutPoint, Transaction, TransactionOutput};
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::path::Path;
+
+
+// how many changed entries we buffer before writing them to the database
+
+pub const DEFAULT_CACHE_CAPACITY: usize = 100_000;
+
+const UTXOS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("utxos");
+
+const META: TableDefinition<&str, &[u8]> = TableDefinition::new("meta");
+
+const TIP_KEY: &str = "tip";
+
+
+// an unspent output, together with where it came from
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UtxoEntry {
+
+    pub output: TransactionOutput,
+
+    // height of the block that created the output
+    pub height: u64,
+
+    // whether it was created by a coinbase transaction, those have to mature before they can be spent
+    pub coinbase: bool,
+}
+
+impl UtxoEntry {
+
+    // whether the output may be spent in a block at `spend_height`
+
+    pub fn is_mature(&self, spend_height: u64, coinbase_maturity: u64) -> bool {
+
+        !self.coinbase || spend_height.saturating_sub(self.height) >= coinbase_maturity
+    }
+}
+
+
+// read access to unspent outputs, transactions are validated against a view
+
+pub trait UtxoView {
+
+    fn get_entry(&self, outpoint: &OutPoint) -> IoResult<Option<UtxoEntry>>;
+}
+
+
+pub struct UtxoSet {
+
+    db: Database,
+
+    // entries changed since the last flush, None means the output was spent
+    cache: HashMap<OutPoint, Option<UtxoEntry>>,
+    capacity: usize,
+
+    // the block this UTXO set is at, including the changes in the cache
+    tip: Hash,
+
+    // outputs spent by a mempool transaction, this is not persisted
+    marked: HashSet<OutPoint>,
+}
+
+impl std::fmt::Debug for UtxoSet {
+
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+
+        f.debug_struct("UtxoSet")
+            .field("cached", &self.cache.len())
+            .field("tip", &self.tip)
+            .finish()
+    }
+}
+
+impl UtxoSet {
+
+    // open the UTXO database at `path`, creating it if it does not exist yet
+
+    pub fn open<P: AsRef<Path>>(path: P, capacity: usize) -> IoResult<Self> {
+
+        let db = Database::create(path).map_err(IoError::other)?;
+
+        // make sure both tables exist, so readers can open them
+
+        let transaction = db.begin_write().map_err(IoError::other)?;
+
+        transaction.open_table(UTXOS).map_err(IoError::other)?;
+        transaction.open_table(META).map_err(IoError::other)?;
+
+        transaction.commit().map_err(IoError::other)?;
+
+        let tip = {
+
+            let transaction = db.begin_read().map_err(IoError::other)?;
+            let meta = transaction.open_table(META).map_err(IoError::other)?;
+
+            match meta.get(TIP_KEY).map_err(IoError::other)? {
+
+                Some(bytes) => Hash::from_bytes(bytes.value().try_into().map_err(|_| {
+
+                    IoError::new(IoErrorKind::InvalidData, "invalid UTXO set tip")
+                })?),
+
+                None => Hash::zero(),
+            }
+        };
+
+        Ok(UtxoSet {
+            db,
+            cache: HashMap::new(),
+            capacity,
+            tip,
+            marked: HashSet::new(),
+        })
+    }
+
+
+    // the hash of the block this UTXO set is at, zero for an empty chain
+
+    pub fn tip(&self) -> Hash {
+
+        self.tip
+    }
+
+
+    pub(crate) fn set_tip(&mut self, tip: Hash) {
+
+        self.tip = tip;
+    }
+
+
+    // the unspent output at `outpoint`, and whether a mempool transaction spends it
+
+    pub fn get(&self, outpoint: &OutPoint) -> IoResult<Option<(bool, UtxoEntry)>> {
+
+        let entry = match self.cache.get(outpoint) {
+
+            Some(entry) => entry.clone(),
+
+            None => self.read(outpoint)?,
+        };
+
+        Ok(entry.map(|entry| (self.marked.contains(outpoint), entry)))
+    }
+
+
+    pub fn contains(&self, outpoint: &OutPoint) -> IoResult<bool> {
+
+        Ok(self.get(outpoint)?.is_some())
+    }
+
+
+    pub(crate) fn insert(&mut self, outpoint: OutPoint, entry: UtxoEntry) {
+
+        self.cache.insert(outpoint, Some(entry));
+    }
+
+
+    // remove an output from the set, returning it if it was unspent
+
+    pub(crate) fn remove(&mut self, outpoint: &OutPoint) -> IoResult<Option<UtxoEntry>> {
+
+        let entry = match self.cache.get(outpoint) {
+
+            Some(entry) => entry.clone(),
+
+            None => self.read(outpoint)?,
+        };
+
+        if entry.is_some() {
+
+            self.cache.insert(*outpoint, None);
+            self.marked.remove(outpoint);
+        }
+
+        Ok(entry)
+    }
+
+
+    pub(crate) fn set_marked(&mut self, outpoint: OutPoint, marked: bool) {
+
+        if marked {
+
+            self.marked.insert(outpoint);
+
+        } else {
+
+            self.marked.remove(&outpoint);
+        }
+    }
+
+
+    // every unspent output matching `predicate`, with its outpoint and mark
+    // this goes over the whole set, so it is slow on a large chain
+
+    pub fn find<F: Fn(&TransactionOutput) -> bool>(&self, predicate: F) -> IoResult<Vec<(OutPoint, bool, UtxoEntry)>> {
+
+        let mut found = vec![];
+
+        let transaction = self.db.begin_read().map_err(IoError::other)?;
+        let table = transaction.open_table(UTXOS).map_err(IoError::other)?;
+
+        for entry in table.iter().map_err(IoError::other)? {
+
+            let (key, value) = entry.map_err(IoError::other)?;
+
+            let outpoint = decode_key(key.value())?;
+
+            // entries in the cache are more recent than the database
+
+            if self.cache.contains_key(&outpoint) {
+
+                continue;
+            }
+
+            let entry = decode(value.value())?;
+
+            if predicate(&entry.output) {
+
+                found.push((outpoint, self.marked.contains(&outpoint), entry));
+            }
+        }
+
+        for (outpoint, entry) in &self.cache {
+
+            if let Some(entry) = entry.as_ref().filter(|entry| predicate(&entry.output)) {
+
+                found.push((*outpoint, self.marked.contains(outpoint), entry.clone()));
+            }
+        }
+
+        Ok(found)
+    }
+
+
+    // whether the cache grew past its capacity and should be written to the database
+
+    pub(crate) fn is_full(&self) -> bool {
+
+        self.cache.len() > self.capacity
+    }
+
+
+    // write every buffered change and the tip to the database, in one transaction
+
+    pub fn flush(&mut self) -> IoResult<()> {
+
+        let transaction = self.db.begin_write().map_err(IoError::other)?;
+
+        {
+            let mut table = transaction.open_table(UTXOS).map_err(IoError::other)?;
+
+            for (outpoint, entry) in &self.cache {
+
+                match entry {
+
+                    Some(entry) => {
+
+                        table.insert(encode_key(outpoint).as_slice(), encode(entry)?.as_slice()).map_err(IoError::other)?;
+                    }
+
+                    None => {
+
+                        table.remove(encode_key(outpoint).as_slice()).map_err(IoError::other)?;
+                    }
+                }
+            }
+
+            let mut meta = transaction.open_table(META).map_err(IoError::other)?;
+
+            meta.insert(TIP_KEY, self.tip.as_bytes().as_slice()).map_err(IoError::other)?;
+        }
+
+        transaction.commit().map_err(IoError::other)?;
+
+        self.cache.clear();
+
+        Ok(())
+    }
+
+
+    // remove every output, in memory and on disk
+
+    pub(crate) fn clear(&mut self) -> IoResult<()> {
+
+        let transaction = self.db.begin_write().map_err(IoError::other)?;
+
+        transaction.delete_table(UTXOS).map_err(IoError::other)?;
+        transaction.open_table(UTXOS).map_err(IoError::other)?;
+
+        {
+            let mut meta = transaction.open_table(META).map_err(IoError::other)?;
+
+            meta.insert(TIP_KEY, Hash::zero().as_bytes().as_slice()).map_err(IoError::other)?;
+        }
+
+        transaction.commit().map_err(IoError::other)?;
+
+        self.cache.clear();
+        self.marked.clear();
+        self.tip = Hash::zero();
+
+        Ok(())
+    }
+
+
+    fn read(&self, outpoint: &OutPoint) -> IoResult<Option<UtxoEntry>> {
+
+        let transaction = self.db.begin_read().map_err(IoError::other)?;
+        let table = transaction.open_table(UTXOS).map_err(IoError::other)?;
+
+        match table.get(encode_key(outpoint).as_slice()).map_err(IoError::other)? {
+
+            Some(value) => Ok(Some(decode(value.value())?)),
+
+            None => Ok(None),
+        }
+    }
+}
+
+
+impl UtxoView for UtxoSet {
+
+    fn get_entry(&self, outpoint: &OutPoint) -> IoResult<Option<UtxoEntry>> {
+
+        Ok(self.get(outpoint)?.map(|(_, entry)| entry))
+    }
+}
+
+
+// the unspent outputs of `base`, plus outputs of transactions that are not in it yet,
+// like the ones earlier in the block being validated
+
+pub struct UtxoOverlay<'a, V: UtxoView> {
+
+    base: &'a V,
+
+    outputs: HashMap<OutPoint, UtxoEntry>,
+}
+
+impl<'a, V: UtxoView> UtxoOverlay<'a, V> {
+
+    pub fn new(base: &'a V) -> Self {
+
+        UtxoOverlay {
+            base,
+            outputs: HashMap::new(),
+        }
+    }
+
+
+    // make the outputs of `transaction`, created at `height`, available for spending
+
+    pub fn add_transaction(&mut self, transaction: &Transaction, height: u64) {
+
+        for (vout, output) in transaction.outputs.iter().enumerate() {
+
+            self.outputs.insert(transaction.outpoint(vout), UtxoEntry {
+                output: output.clone(),
+                height,
+                coinbase: transaction.is_coinbase(),
+            });
+        }
+    }
+}
+
+impl<V: UtxoView> UtxoView for UtxoOverlay<'_, V> {
+
+    fn get_entry(&self, outpoint: &OutPoint) -> IoResult<Option<UtxoEntry>> {
+
+        match self.outputs.get(outpoint) {
+
+            Some(entry) => Ok(Some(entry.clone())),
+
+            None => self.base.get_entry(outpoint),
+        }
+    }
+}
+
+
+// database keys are the transaction id followed by the big endian output index
+
+fn encode_key(outpoint: &OutPoint) -> [u8; 36] {
+
+    let mut key = [0u8; 36];
+
+    key[..32].copy_from_slice(&outpoint.txid.as_bytes());
+    key[32..].copy_from_slice(&outpoint.vout.to_be_bytes());
+
+    key
+}
+
+
+fn decode_key(bytes: &[u8]) -> IoResult<OutPoint> {
+
+    let invalid = || IoError::new(IoErrorKind::InvalidData, "invalid UTXO key");
+
+    let (txid, vout) = bytes.split_first_chunk::<32>().ok_or_else(invalid)?;
+
+    Ok(OutPoint {
+        txid: Hash::from_bytes(*txid),
+        vout: u32::from_be_bytes(vout.try_into().map_err(|_| invalid())?),
+    })
+}
+
+
+fn encode(entry: &UtxoEntry) -> IoResult<Vec<u8>> {
+
+    let mut bytes = vec![];
+
+    ciborium::ser::into_writer(entry, &mut bytes).map_err(|_| {
+
+        IoError::new(IoErrorKind::InvalidData, "failed to serialize UTXO")
+    })?;
+
+    Ok(bytes)
+}
+
+
+fn decode(bytes: &[u8]) -> IoResult<UtxoEntry> {
+
+    ciborium::de::from_reader(bytes).map_err(|_| {
+
+        IoError::new(IoErrorKind::InvalidData, "failed to deserialize UTXO")
+    })
+}
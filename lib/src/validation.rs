@@ -0,0 +1,815 @@
+// Block validation, split up into named consensus rules.
+// Every block that extends the active chain goes through all of them, in this order:
+//
+//   header linkage  the block builds on the current tip
+//   timestamp       the block is newer than the median of the blocks before it, and not from the future
+//   proof of work   the header carries the target the chain expects, and its hash meets it
+//   size            the block, and every transaction in it, stays within the size and signature check limits
+//   merkle root     the header commits to exactly the block's transactions
+//   coinbase        the first transaction, and only the first one, is a coinbase committing to the block height
+//   outputs         no transaction appears twice, its outputs would overwrite each other
+//   inputs          every input spends an unspent output, at most once, and coinbase outputs only once they matured
+//                   an output created earlier in the same block counts as unspent
+//   signatures      every input is signed by the owner of the output it spends, over the parts of
+//                   the transaction its signature hash type covers
+//   amounts         no transaction creates money, the coinbase pays exactly reward plus fees
+//
+// The first rule that fails is reported, together with the transaction and input it failed on.
+//
+// The rules about a single transaction are bundled in validate_transaction(), which is also what
+// the mempool uses. Mempool transactions additionally have to follow the policy rules.
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::amount::Amount;
+use crate::error::Result;
+use crate::params::ChainParams;
+use crate::sha256::Hash;
+use crate::types::{Block, BlockHeader, Transaction, TransactionOutput};
+use crate::util::MerkleRoot;
+use crate::utxo::{UtxoEntry, UtxoSet, UtxoView};
+use crate::U256;
+
+use std::collections::HashSet;
+use std::fmt;
+
+
+// the timestamp of a block has to be later than the median timestamp of this many blocks before it
+
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+
+// consensus limits on the serialized size of blocks and transactions, in bytes
+
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
+pub const MAX_TRANSACTION_SIZE: usize = 100_000;
+
+
+// every input needs one signature check, this bounds the work a single block can cause
+
+pub const MAX_BLOCK_SIGNATURE_CHECKS: usize = 20_000;
+
+
+// the extra nonce of a coinbase can hold at most this many bytes
+
+pub const MAX_COINBASE_EXTRA_NONCE_SIZE: usize = 100;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+
+    HeaderLinkage,
+
+    Timestamp,
+
+    ProofOfWork,
+
+    Size,
+
+    MerkleRoot,
+
+    Coinbase,
+
+    Outputs,
+
+    Inputs,
+
+    Signatures,
+
+    Amounts,
+
+    // not a consensus rule, only applies to mempool transactions
+    Policy,
+}
+
+impl fmt::Display for Rule {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+        let name = match self {
+
+            Rule::HeaderLinkage => "header linkage",
+
+            Rule::Timestamp => "timestamp",
+
+            Rule::ProofOfWork => "proof of work",
+
+            Rule::Size => "size",
+
+            Rule::MerkleRoot => "merkle root",
+
+            Rule::Coinbase => "coinbase",
+
+            Rule::Outputs => "outputs",
+
+            Rule::Inputs => "inputs",
+
+            Rule::Signatures => "signatures",
+
+            Rule::Amounts => "amounts",
+
+            Rule::Policy => "policy",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+
+// which rule a block broke, and where
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleViolation {
+
+    pub rule: Rule,
+
+    // index of the offending transaction in the block, if the rule is about a transaction
+    pub transaction: Option<usize>,
+
+    // index of the offending input in that transaction, if the rule is about an input
+    pub input: Option<usize>,
+
+    pub reason: &'static str,
+}
+
+impl RuleViolation {
+
+    pub(crate) fn block(rule: Rule, reason: &'static str) -> Self {
+
+        RuleViolation { rule, transaction: None, input: None, reason }
+    }
+
+
+    pub(crate) fn transaction(rule: Rule, transaction: usize, reason: &'static str) -> Self {
+
+        RuleViolation { rule, transaction: Some(transaction), input: None, reason }
+    }
+
+
+    pub(crate) fn input(rule: Rule, transaction: usize, input: usize, reason: &'static str) -> Self {
+
+        RuleViolation { rule, transaction: Some(transaction), input: Some(input), reason }
+    }
+
+
+    // a violation found while looking at a transaction on its own, see in_transaction()
+
+    pub(crate) fn standalone(rule: Rule, reason: &'static str) -> Self {
+
+        RuleViolation { rule, transaction: None, input: None, reason }
+    }
+
+
+    pub(crate) fn for_input(rule: Rule, input: usize, reason: &'static str) -> Self {
+
+        RuleViolation { rule, transaction: None, input: Some(input), reason }
+    }
+
+
+    // the same violation, placed at the index of the transaction in its block
+
+    pub fn in_transaction(self, transaction: usize) -> Self {
+
+        RuleViolation { transaction: Some(transaction), ..self }
+    }
+}
+
+impl fmt::Display for RuleViolation {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+        write!(f, "{} rule violated", self.rule)?;
+
+        if let Some(transaction) = self.transaction {
+
+            write!(f, " by transaction {}", transaction)?;
+        }
+
+        if let Some(input) = self.input {
+
+            write!(f, ", input {}", input)?;
+        }
+
+        write!(f, ": {}", self.reason)
+    }
+}
+
+impl std::error::Error for RuleViolation {}
+
+
+// how strictly a transaction is checked
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationLevel {
+
+    // the consensus rules, for transactions in a block
+    Consensus,
+
+    // consensus and policy rules, for transactions entering the mempool
+    Policy,
+}
+
+
+// the state of the chain a new block is validated against
+
+#[derive(Debug, Clone)]
+pub struct ChainContext {
+
+    // hash of the block the new one has to build on
+    pub prev_block_hash: Hash,
+
+    // height the new block will have
+    pub height: u64,
+
+    // see median_time_past()
+    pub median_time_past: DateTime<Utc>,
+
+    // the target the new block has to carry
+    pub target: U256,
+}
+
+
+// run every rule against a block that is to be added to the chain described by `context`
+
+pub fn validate_block(block: &Block, context: &ChainContext, utxos: &UtxoSet, params: &ChainParams) -> Result<()> {
+
+    check_header_linkage(&block.header, context.prev_block_hash)?;
+
+    check_timestamp(&block.header, context.median_time_past, Utc::now(), params)?;
+
+    check_target(&block.header, context.target)?;
+
+    check_proof_of_work(&block.header)?;
+
+    check_size(block)?;
+
+    check_merkle_root(block)?;
+
+    block.verify_transaction(context.height, utxos, params)
+}
+
+
+pub fn check_header_linkage(header: &BlockHeader, prev_block_hash: Hash) -> std::result::Result<(), RuleViolation> {
+
+    if header.prev_block_hash != prev_block_hash {
+
+        return Err(RuleViolation::block(Rule::HeaderLinkage, "previous block hash is not the tip"));
+    }
+
+    Ok(())
+}
+
+
+// the median timestamp of the last MEDIAN_TIME_SPAN of `headers`, or of all of them if there are fewer
+// the median moves forward even if a few miners lie about the time
+
+pub fn median_time_past<'a>(headers: impl DoubleEndedIterator<Item = &'a BlockHeader>) -> Option<DateTime<Utc>> {
+
+    let mut timestamps: Vec<DateTime<Utc>> = headers
+        .rev()
+        .take(MEDIAN_TIME_SPAN)
+        .map(|header| header.timestamp)
+        .collect();
+
+    timestamps.sort();
+
+    timestamps.get(timestamps.len() / 2).copied()
+}
+
+
+pub fn check_timestamp(
+    header: &BlockHeader,
+    median_time_past: DateTime<Utc>,
+    now: DateTime<Utc>,
+    params: &ChainParams,
+) -> std::result::Result<(), RuleViolation> {
+
+    if header.timestamp <= median_time_past {
+
+        return Err(RuleViolation::block(Rule::Timestamp, "timestamp is not after the median time of the previous blocks"));
+    }
+
+    if header.timestamp > now + Duration::seconds(params.max_future_block_time as i64) {
+
+        return Err(RuleViolation::block(Rule::Timestamp, "timestamp is too far in the future"));
+    }
+
+    Ok(())
+}
+
+
+// miners do not get to pick their own difficulty
+
+pub fn check_target(header: &BlockHeader, expected_target: U256) -> std::result::Result<(), RuleViolation> {
+
+    if header.bits != expected_target.to_compact() {
+
+        return Err(RuleViolation::block(Rule::ProofOfWork, "target is not the one the chain expects"));
+    }
+
+    Ok(())
+}
+
+
+pub fn check_proof_of_work(header: &BlockHeader) -> std::result::Result<(), RuleViolation> {
+
+    if U256::from_compact(header.bits).is_none() {
+
+        return Err(RuleViolation::block(Rule::ProofOfWork, "invalid compact target"));
+    }
+
+    if !header.hash().matches_target(header.target()) {
+
+        return Err(RuleViolation::block(Rule::ProofOfWork, "header hash does not match its target"));
+    }
+
+    Ok(())
+}
+
+
+// the block as a whole, before looking at anything inside it
+// the size of every single transaction is checked by validate_transaction()
+// counting the signature checks is cheaper than serializing the block, so it comes first
+
+pub fn check_size(block: &Block) -> std::result::Result<(), RuleViolation> {
+
+    let signature_checks: usize = block.transactions
+        .iter()
+        .map(|transaction| transaction.inputs.len())
+        .sum();
+
+    if signature_checks > MAX_BLOCK_SIGNATURE_CHECKS {
+
+        return Err(RuleViolation::block(Rule::Size, "block needs too many signature checks"));
+    }
+
+    if block.serialized_size() > MAX_BLOCK_SIZE {
+
+        return Err(RuleViolation::block(Rule::Size, "block is too large"));
+    }
+
+    Ok(())
+}
+
+
+pub fn check_merkle_root(block: &Block) -> std::result::Result<(), RuleViolation> {
+
+    if MerkleRoot::calculate(&block.transactions) != block.header.merkle_root {
+
+        return Err(RuleViolation::block(Rule::MerkleRoot, "merkle root does not match the transactions"));
+    }
+
+    Ok(())
+}
+
+
+// the first transaction is the coinbase of the block at `height`: it has no inputs and pays out at least once
+// the other transactions are checked by validate_transaction()
+
+pub fn check_coinbase(block: &Block, height: u64) -> std::result::Result<(), RuleViolation> {
+
+    let Some(coinbase) = block.transactions.first() else {
+
+        return Err(RuleViolation::block(Rule::Coinbase, "block has no transactions"));
+    };
+
+    let Some(coinbase_input) = &coinbase.coinbase else {
+
+        return Err(RuleViolation::transaction(Rule::Coinbase, 0, "first transaction is not a coinbase"));
+    };
+
+    if coinbase_input.height != height {
+
+        return Err(RuleViolation::transaction(Rule::Coinbase, 0, "coinbase does not commit to the block height"));
+    }
+
+    if coinbase_input.extra_nonce.len() > MAX_COINBASE_EXTRA_NONCE_SIZE {
+
+        return Err(RuleViolation::transaction(Rule::Coinbase, 0, "coinbase extra nonce is too long"));
+    }
+
+    if !coinbase.inputs.is_empty() {
+
+        return Err(RuleViolation::transaction(Rule::Coinbase, 0, "coinbase has inputs"));
+    }
+
+    if coinbase.outputs.is_empty() {
+
+        return Err(RuleViolation::transaction(Rule::Coinbase, 0, "coinbase has no outputs"));
+    }
+
+    Ok(())
+}
+
+
+// outputs are identified by the id of their transaction and their index,
+// a transaction included twice would create the same outputs twice
+// (across blocks this can not happen: every coinbase commits to its height,
+// and every other transaction spends outputs that exist only once)
+
+pub fn check_outputs(block: &Block) -> std::result::Result<(), RuleViolation> {
+
+    let mut txids = HashSet::new();
+
+    for (idx, transaction) in block.transactions.iter().enumerate() {
+
+        if !txids.insert(transaction.hash()) {
+
+            return Err(RuleViolation::transaction(Rule::Outputs, idx, "transaction is included twice"));
+        }
+    }
+
+    Ok(())
+}
+
+
+// two transactions of the same block can not spend the same output
+// every transaction is validated against the UTXO set plus the outputs before it in the block,
+// neither of which knows what the other transactions spend, so this is not caught there
+
+pub fn check_double_spends(block: &Block) -> std::result::Result<(), RuleViolation> {
+
+    let mut spending = HashSet::new();
+
+    for (tx_idx, transaction) in block.transactions.iter().enumerate() {
+
+        for (input_idx, input) in transaction.inputs.iter().enumerate() {
+
+            if !spending.insert(input.previous_output) {
+
+                return Err(RuleViolation::input(Rule::Inputs, tx_idx, input_idx, "output is spent twice in the block"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+// everything a transaction that is not a coinbase has to satisfy on its own,
+// checked against the outputs in `utxos` for a block at `height`
+// mempool transactions additionally have to follow the policy rules, blocks do not
+// returns the fee the transaction pays
+
+pub fn validate_transaction<V: UtxoView>(
+    transaction: &Transaction,
+    utxos: &V,
+    height: u64,
+    params: &ChainParams,
+    level: ValidationLevel,
+) -> Result<Amount> {
+
+    check_spends(transaction)?;
+
+    check_transaction_size(transaction)?;
+
+    let spent = resolve_inputs(transaction, utxos)?;
+
+    check_maturity(height, &spent, params)?;
+
+    check_signatures(transaction, &spent)?;
+
+    let fee = check_amounts(transaction, &spent)?;
+
+    if level == ValidationLevel::Policy {
+
+        check_policy(transaction)?;
+    }
+
+    Ok(fee)
+}
+
+
+// the fee a transaction pays, without checking anything but its amounts
+
+pub fn transaction_fee<V: UtxoView>(transaction: &Transaction, utxos: &V) -> Result<Amount> {
+
+    let spent = resolve_inputs(transaction, utxos)?;
+
+    Ok(check_amounts(transaction, &spent)?)
+}
+
+
+// a coinbase is only valid as the first transaction of a block, everything else has to spend something
+
+pub fn check_spends(transaction: &Transaction) -> std::result::Result<(), RuleViolation> {
+
+    if transaction.is_coinbase() {
+
+        return Err(RuleViolation::standalone(Rule::Coinbase, "only the first transaction of a block can be a coinbase"));
+    }
+
+    if transaction.inputs.is_empty() {
+
+        return Err(RuleViolation::standalone(Rule::Coinbase, "transaction has no inputs"));
+    }
+
+    Ok(())
+}
+
+
+pub fn check_transaction_size(transaction: &Transaction) -> std::result::Result<(), RuleViolation> {
+
+    if transaction.serialized_size() > MAX_TRANSACTION_SIZE {
+
+        return Err(RuleViolation::standalone(Rule::Size, "transaction is too large"));
+    }
+
+    Ok(())
+}
+
+
+// look up the output every input spends
+
+pub fn resolve_inputs<V: UtxoView>(transaction: &Transaction, utxos: &V) -> Result<Vec<UtxoEntry>> {
+
+    let mut spending = HashSet::new();
+
+    let mut spent = vec![];
+
+    for (idx, input) in transaction.inputs.iter().enumerate() {
+
+        let Some(entry) = utxos.get_entry(&input.previous_output)? else {
+
+            return Err(RuleViolation::for_input(Rule::Inputs, idx, "output is not unspent").into());
+        };
+
+        if !spending.insert(input.previous_output) {
+
+            return Err(RuleViolation::for_input(Rule::Inputs, idx, "output is spent twice in the transaction").into());
+        }
+
+        spent.push(entry);
+    }
+
+    Ok(spent)
+}
+
+
+// a coinbase output can only be spent once `coinbase_maturity` blocks were built on top of it,
+// so a reorg that drops the coinbase can not take spends of it down with it
+
+pub fn check_maturity(height: u64, spent: &[UtxoEntry], params: &ChainParams) -> std::result::Result<(), RuleViolation> {
+
+    for (idx, entry) in spent.iter().enumerate() {
+
+        if !entry.is_mature(height, params.coinbase_maturity) {
+
+            return Err(RuleViolation::for_input(Rule::Inputs, idx, "coinbase output is not mature yet"));
+        }
+    }
+
+    Ok(())
+}
+
+
+pub fn check_signatures(transaction: &Transaction, spent: &[UtxoEntry]) -> std::result::Result<(), RuleViolation> {
+
+    for (idx, (input, entry)) in transaction.inputs.iter().zip(spent).enumerate() {
+
+        let Some(signature_hash) = transaction.signature_hash(idx, input.sighash) else {
+
+            return Err(RuleViolation::for_input(Rule::Signatures, idx, "signature hash type single without a matching output"));
+        };
+
+        if !input.signature.verify(&signature_hash, &entry.output.pubkey) {
+
+            return Err(RuleViolation::for_input(Rule::Signatures, idx, "invalid signature"));
+        }
+    }
+
+    Ok(())
+}
+
+
+// a transaction pays at most what it spends, the difference is its fee
+
+pub fn check_amounts(transaction: &Transaction, spent: &[UtxoEntry]) -> std::result::Result<Amount, RuleViolation> {
+
+    let input_value = sum(spent.iter().map(|entry| &entry.output))
+        .ok_or(RuleViolation::standalone(Rule::Amounts, "input value is out of range"))?;
+
+    let output_value = sum(transaction.outputs.iter())
+        .ok_or(RuleViolation::standalone(Rule::Amounts, "output value is out of range"))?;
+
+    input_value
+        .checked_sub(output_value)
+        .ok_or(RuleViolation::standalone(Rule::Amounts, "outputs are worth more than inputs"))
+}
+
+
+// not consensus, but what we are willing to relay and mine:
+// a transaction has to pay someone, and every output has to be worth something
+
+pub fn check_policy(transaction: &Transaction) -> std::result::Result<(), RuleViolation> {
+
+    if transaction.outputs.is_empty() {
+
+        return Err(RuleViolation::standalone(Rule::Policy, "transaction has no outputs"));
+    }
+
+    if transaction.outputs.iter().any(|output| output.value == Amount::ZERO) {
+
+        return Err(RuleViolation::standalone(Rule::Policy, "output is worth nothing"));
+    }
+
+    Ok(())
+}
+
+
+// the coinbase collects the block reward plus every fee in the block, no more and no less
+
+pub fn check_coinbase_value(block: &Block, reward: Amount, fees: Amount) -> std::result::Result<(), RuleViolation> {
+
+    let coinbase_value = sum(block.transactions[0].outputs.iter())
+        .ok_or(RuleViolation::transaction(Rule::Amounts, 0, "coinbase value is out of range"))?;
+
+    if Some(coinbase_value) != reward.checked_add(fees) {
+
+        return Err(RuleViolation::transaction(Rule::Amounts, 0, "coinbase does not pay block reward plus fees"));
+    }
+
+    Ok(())
+}
+
+
+// the total value of `outputs`, None if it is larger than MAX_MONEY
+
+fn sum<'a>(outputs: impl Iterator<Item = &'a TransactionOutput>) -> Option<Amount> {
+
+    Amount::checked_sum(outputs.map(|output| output.value))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::types::{OutPoint, SigHashType};
+
+
+    // a header with the given timestamp, the rest does not matter for the timestamp rule
+
+    fn header_at(timestamp: DateTime<Utc>) -> BlockHeader {
+
+        let coinbase = Transaction::new_coinbase(0, vec![], vec![]);
+
+        BlockHeader::new(timestamp, 0, Hash::zero(), MerkleRoot::calculate(&[coinbase]), 0x207fffff)
+    }
+
+
+    #[test]
+    fn median_time_past_is_the_median_of_the_last_blocks() {
+
+        let start = Utc::now();
+
+        assert_eq!(median_time_past([].iter()), None);
+
+        // fewer blocks than the span, out of order like miners' clocks
+
+        let headers: Vec<BlockHeader> = [3, 1, 2].iter().map(|s| header_at(start + Duration::seconds(*s))).collect();
+
+        assert_eq!(median_time_past(headers.iter()), Some(start + Duration::seconds(2)));
+
+        // only the last MEDIAN_TIME_SPAN count, the early ones with huge timestamps are ignored
+
+        let headers: Vec<BlockHeader> = (0..MEDIAN_TIME_SPAN as i64 + 5)
+            .map(|n| header_at(start + Duration::seconds(if n < 5 { 1_000_000 } else { n })))
+            .collect();
+
+        assert_eq!(median_time_past(headers.iter()), Some(start + Duration::seconds(5 + MEDIAN_TIME_SPAN as i64 / 2)));
+    }
+
+
+    #[test]
+    fn rejects_timestamps_not_after_the_median_time_past() {
+
+        let params = ChainParams::regtest();
+        let now = Utc::now();
+        let median = now - Duration::hours(1);
+
+        let error = check_timestamp(&header_at(median), median, now, &params).unwrap_err();
+
+        assert!(error.to_string().contains("not after the median time"), "{}", error);
+
+        check_timestamp(&header_at(median + Duration::seconds(1)), median, now, &params).unwrap();
+    }
+
+
+    #[test]
+    fn rejects_timestamps_too_far_in_the_future() {
+
+        let params = ChainParams::regtest();
+        let now = Utc::now();
+        let median = now - Duration::hours(1);
+        let limit = now + Duration::seconds(params.max_future_block_time as i64);
+
+        check_timestamp(&header_at(limit), median, now, &params).unwrap();
+
+        let error = check_timestamp(&header_at(limit + Duration::seconds(1)), median, now, &params).unwrap_err();
+
+        assert!(error.to_string().contains("too far in the future"), "{}", error);
+    }
+
+
+    // a transaction with `outputs` outputs and no inputs, only good for filling up space
+
+    fn filler(outputs: usize) -> Transaction {
+
+        let key = PrivateKey::new_key();
+
+        let outputs = (0..outputs)
+            .map(|_| TransactionOutput { value: Amount::from_sat(1_000), pubkey: key.public_key() })
+            .collect();
+
+        Transaction::new(vec![], outputs)
+    }
+
+
+    fn block_of(transactions: Vec<Transaction>) -> Block {
+
+        Block::new(header_at(Utc::now()), transactions)
+    }
+
+
+    #[test]
+    fn rejects_transactions_over_the_size_limit() {
+
+        let output_size = filler(2).serialized_size() - filler(1).serialized_size();
+
+        // a little under the limit, then one output at a time until it is over
+
+        let mut transaction = filler(MAX_TRANSACTION_SIZE / output_size - 10);
+
+        while transaction.serialized_size() <= MAX_TRANSACTION_SIZE {
+
+            check_transaction_size(&transaction).unwrap();
+
+            transaction.outputs.push(transaction.outputs[0].clone());
+        }
+
+        let error = check_transaction_size(&transaction).unwrap_err();
+
+        assert!(error.to_string().contains("transaction is too large"), "{}", error);
+    }
+
+
+    #[test]
+    fn rejects_blocks_over_the_size_limit() {
+
+        let transaction = filler(500);
+
+        // just enough copies to go over, two less stay within the limit
+
+        let copies = MAX_BLOCK_SIZE / transaction.serialized_size() + 1;
+
+        let block = block_of(vec![transaction.clone(); copies]);
+
+        assert!(block.serialized_size() > MAX_BLOCK_SIZE);
+
+        let error = check_size(&block).unwrap_err();
+
+        assert!(error.to_string().contains("block is too large"), "{}", error);
+
+        let block = block_of(vec![transaction; copies - 2]);
+
+        assert!(block.serialized_size() <= MAX_BLOCK_SIZE);
+
+        check_size(&block).unwrap();
+    }
+
+
+    #[test]
+    fn rejects_blocks_over_the_signature_check_limit() {
+
+        let key = PrivateKey::new_key();
+
+        let outpoint = OutPoint { txid: Hash::zero(), vout: 0 };
+
+        let mut transaction = Transaction::new_signed(&[(outpoint, &key)], filler(1).outputs, SigHashType::ALL).unwrap();
+
+        let input = transaction.inputs[0].clone();
+
+        transaction.inputs = vec![input; MAX_BLOCK_SIGNATURE_CHECKS / 2];
+
+        // spread over two transactions, what counts is the whole block
+
+        // exactly at the limit, such a block is still far too large
+
+        let block = block_of(vec![transaction.clone(), transaction.clone()]);
+
+        let error = check_size(&block).unwrap_err();
+
+        assert!(error.to_string().contains("block is too large"), "{}", error);
+
+        transaction.inputs.push(transaction.inputs[0].clone());
+
+        let block = block_of(vec![transaction.clone(), transaction]);
+
+        let error = check_size(&block).unwrap_err();
+
+        assert!(error.to_string().contains("block needs too many signature checks"), "{}", error);
+    }
+}
@@ -5,6 +5,7 @@ use std::{env};
 use std::process::exit;
 use lib::crypto::PublicKey;
 use lib::network::Message;
+use lib::params::{ChainParams, Network};
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 use tokio::time::{interval, Duration};
@@ -44,12 +45,19 @@ use std::sync::{
 
         #[arg(short, long)]
         public_key_file: String,
+
+        // the network the node is running: mainnet, testnet or regtest
+        #[arg(short, long, default_value_t = Network::Mainnet)]
+        network: Network,
     }
 
 struct Miner{
 
     public_key: PublicKey,
 
+    // magic bytes of the network we mine on
+    magic: [u8; 4],
+
     stream: Mutex<TcpStream>,
 
     current_template: Arc<std::sync::Mutex<Option<Block>>>,
@@ -67,7 +75,7 @@ struct Miner{
 
 impl Miner {
 
-    async fn new(address: String, public_key: PublicKey) -> Result<Self> {
+    async fn new(address: String, public_key: PublicKey, magic: [u8; 4]) -> Result<Self> {
 
 
         let stream = TcpStream::connect(&address).await?;
@@ -78,6 +86,8 @@ impl Miner {
 
             public_key,
 
+            magic,
+
             stream: Mutex::new(stream),
 
             current_template: Arc::new(std::sync::Mutex::new(None,)),  // // Arc (Atomic Reference Count)
@@ -143,7 +153,7 @@ impl Miner {
 
                 if let Some(mut block) = template.lock().unwrap().clone() {
 
-                    println!("Mining block with target: {}", block.header.target );
+                    println!("Mining block with target: {}", block.header.target() );
 
                 
 
@@ -199,7 +209,7 @@ impl Miner {
 
         let mut stream_lock = self.stream.lock().await;
 
-        message.send_async(&mut *stream_lock).await?;
+        message.send_async(self.magic, &mut *stream_lock).await?;
         
         // This explicitly drops the lock on the stream, releasing the Mutex or RwLock.
         //This is important because once the message is sent, the function no longer needs exclusive access to the stream
@@ -207,13 +217,13 @@ impl Miner {
 
         
         let mut stream_lock = self.stream.lock().await;
-        match Message::recieve_asynce(&mut *stream_lock).await? {
+        match Message::recieve_asynce(self.magic, &mut *stream_lock).await? {
 
             Message::Template(template) =>  {
 
                 drop(stream_lock);
                 
-                println!("received new template with target: {}", template.header.target);
+                println!("received new template with target: {}", template.header.target());
 
                 *self.current_template.lock().unwrap() = Some(template);
 
@@ -239,13 +249,13 @@ impl Miner {
 
             let mut stream_lock = self.stream.lock().await;
 
-            message.send_async(&mut *stream_lock).await?;
+            message.send_async(self.magic, &mut *stream_lock).await?;
 
             drop(stream_lock);
 
             let mut stream_lock = self.stream.lock().await;
 
-            match Message::recieve_asynce(&mut *stream_lock).await? {
+            match Message::recieve_asynce(self.magic, &mut *stream_lock).await? {
 
                 Message::TemplateValidity(valid) => {
 
@@ -287,7 +297,7 @@ impl Miner {
 
         let mut stream_lock = self.stream.lock().await;
 
-        message.send_async(&mut *stream_lock).await?;
+        message.send_async(self.magic, &mut *stream_lock).await?;
         
         self.mining.store(false, Ordering::Relaxed);
 
@@ -332,7 +342,9 @@ async fn main() -> Result<()>{
                                     anyhow!("Error reading public key: {}", e)
                                     })?;
 
-    let miner = Miner::new(cli.address, public_key).await?;
+    let params = ChainParams::for_network(cli.network);
+
+    let miner = Miner::new(cli.address, public_key, params.magic).await?;
     
     miner.run().await
 
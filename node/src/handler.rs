@@ -0,0 +1,266 @@
+use lib::mempool::TransactionRejection;
+use lib::network::Message;
+use lib::template::TemplateLimits;
+use lib::types::Transaction;
+
+use tokio::net::TcpStream;
+use std::sync::Arc;
+
+use crate::{peers, sync, Node};
+
+
+// serve one peer (a miner, a wallet or another node) until it disconnects
+
+pub async fn handle_connection(mut socket: TcpStream, node: Arc<Node>) {
+
+    loop {
+
+        // read a message from the socket, a failed read means the peer went away
+
+        let message = match Message::recieve_asynce(node.params.magic, &mut socket).await {
+
+            Ok(message) => message,
+
+            Err(e) => {
+
+                println!("connection closed: {}", e);
+
+                return;
+            }
+        };
+
+        let response = match handle_message(message, &node).await {
+
+            Some(response) => response,
+
+            None => continue,
+        };
+
+        if let Err(e) = response.send_async(node.params.magic, &mut socket).await {
+
+            println!("failed to send response: {}, closing connection", e);
+
+            return;
+        }
+    }
+}
+
+
+// returns the message to answer with, if the request expects one
+
+async fn handle_message(message: Message, node: &Arc<Node>) -> Option<Message> {
+
+    use Message::*;
+
+    match message {
+
+        FetchUTXOS(public_key) => {
+
+            println!("received request to fetch UTXOs");
+
+            let blockchain = node.blockchain.read().await;
+
+            let utxos = match blockchain.utxos().find(|output| output.pubkey == public_key) {
+
+                Ok(utxos) => utxos,
+
+                Err(e) => {
+
+                    println!("failed to read UTXOs: {}", e);
+
+                    return None;
+                }
+            };
+
+            Some(UTXOS(utxos.into_iter().map(|(outpoint, marked, entry)| (outpoint, entry.output, marked)).collect()))
+        }
+
+        // a client waits for the outcome, other nodes relaying a transaction do not
+
+        SubmitTransaction(transaction) => {
+
+            let hash = transaction.hash();
+
+            Some(TransactionStatus(hash, accept_transaction(transaction, node).await))
+        }
+
+        NewTransaction(transaction) => {
+
+            let _ = accept_transaction(transaction, node).await;
+
+            None
+        }
+
+        FetchTemplate(public_key) => {
+
+            println!("received request for a block template");
+
+            let blockchain = node.blockchain.read().await;
+
+            match blockchain.build_template(public_key, TemplateLimits::default()) {
+
+                Ok(template) => Some(Template(template)),
+
+                Err(e) => {
+
+                    println!("failed to build a block template: {}", e);
+
+                    None
+                }
+            }
+        }
+
+        ValidateTemplate(template) => {
+
+            // the template stays valid as long as nobody extended the chain in the meantime
+
+            let blockchain = node.blockchain.read().await;
+
+            Some(TemplateValidity(template.header.prev_block_hash == blockchain.tip_hash()))
+        }
+
+        SubmitTemplate(block) | NewBlock(block) => {
+
+            let hash = block.hash();
+
+            if node.seen.lock().await.contains(&hash) {
+
+                return None;
+            }
+
+            println!("received block {}", hash);
+
+            let mut blockchain = node.blockchain.write().await;
+
+            // a block on a branch we have not seen yet, the sender is ahead of us or on another fork
+            // a sync finds the peer with the most work and downloads the branch from where it forks off
+
+            if !blockchain.contains_block(&block.header.prev_block_hash) {
+
+                // only a block that was actually mined can make us sync, anyone can make up the rest
+
+                if let Err(e) = blockchain.check_work(&block.header) {
+
+                    println!("block rejected: {}", e);
+
+                    return None;
+                }
+
+                println!("block {} builds on a block we do not know, syncing", hash);
+
+                let node = node.clone();
+
+                tokio::spawn(async move { sync::download_blockchain(&node).await });
+
+                return None;
+            }
+
+            if let Err(e) = blockchain.add_block(block.clone()) {
+
+                println!("block rejected: {}", e);
+
+                return None;
+            }
+
+            println!("block accepted, height is now {}", blockchain.blocks_height());
+
+            drop(blockchain);
+
+            node.seen.lock().await.insert(hash);
+
+            peers::broadcast(node, &NewBlock(block)).await;
+
+            None
+        }
+
+        FetchBlock(height) => {
+
+            let blockchain = node.blockchain.read().await;
+
+            match blockchain.block_at(height as u64) {
+
+                Ok(block) => block.map(NewBlock),
+
+                Err(e) => {
+
+                    println!("failed to read block {}: {}", height, e);
+
+                    None
+                }
+            }
+        }
+
+        AskDifference(height) => {
+
+            let blockchain = node.blockchain.read().await;
+
+            let difference = blockchain.blocks_height() as i32 - height as i32;
+
+            Some(Difference(difference))
+        }
+
+        AskChainWork => {
+
+            let blockchain = node.blockchain.read().await;
+
+            Some(ChainWork(blockchain.chain_work(), blockchain.blocks_height()))
+        }
+
+        DiscoverNodes => {
+
+            let nodes = node.nodes.read().await.clone();
+
+            Some(NodeList(nodes))
+        }
+
+        // a node announcing itself (or other nodes) to us, connect to the ones we do not know yet
+
+        NodeList(nodes) => {
+
+            let node = node.clone();
+
+            tokio::spawn(async move { peers::discover(&node, nodes).await });
+
+            None
+        }
+
+        // these are answers to requests, nobody should send them to us unprompted
+
+        UTXOS(_) | TransactionStatus(..) | Template(_) | TemplateValidity(_) | Difference(_) | ChainWork(..) => {
+
+            println!("received an unexpected response message, ignoring it");
+
+            None
+        }
+    }
+}
+
+
+// add a transaction to the mempool and relay it
+// only what we accepted is relayed, and only the first time we see it
+
+async fn accept_transaction(transaction: Transaction, node: &Arc<Node>) -> Result<(), TransactionRejection> {
+
+    let hash = transaction.hash();
+
+    if node.seen.lock().await.contains(&hash) {
+
+        return Ok(());
+    }
+
+    println!("received transaction {}", hash);
+
+    if let Err(e) = node.blockchain.write().await.add_to_mempool(transaction.clone()) {
+
+        println!("transaction rejected: {}", e);
+
+        return Err(e.into());
+    }
+
+    node.seen.lock().await.insert(hash);
+
+    peers::broadcast(node, &Message::NewTransaction(transaction)).await;
+
+    Ok(())
+}
+
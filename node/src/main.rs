@@ -1,3 +1,391 @@
-fn main() {
-    println!("Hello, world!");
+use lib::crypto::PublicKey;
+use lib::network::Message;
+use lib::params::{ChainParams, Network};
+use lib::template::TemplateLimits;
+use lib::types::Blockchain;
+use lib::util::Saveable;
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::time::{interval, Duration};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+
+mod handler;
+mod peers;
+mod sync;
+
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// how long to wait before accepting again after accepting a connection failed,
+// running out of file descriptors for example does not go away right away
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+
+    // the chain to run: mainnet, testnet or regtest
+    #[arg(short, long, default_value_t = Network::Mainnet)]
+    network: Network,
+
+    // defaults to the network's default port
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    // address other nodes can reach us on, defaults to 127.0.0.1:<port>
+    #[arg(short, long)]
+    address: Option<String>,
+
+    // directory the blockchain is stored in, networks other than mainnet use a subdirectory
+    #[arg(short, long, default_value = "data")]
+    data_dir: PathBuf,
+
+    // how many megabytes of transactions the mempool may hold, defaults to the network's limit
+    #[arg(long)]
+    max_mempool: Option<usize>,
+
+    // seed nodes, the rest of the network is discovered through them
+    nodes: Vec<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+
+#[derive(Subcommand)]
+enum Command {
+
+    // mine `count` blocks on top of the stored chain, paying the key in `public_key_file`,
+    // and exit without starting the node (regtest only)
+    Generate {
+
+        count: u64,
+
+        public_key_file: PathBuf,
+    },
+}
+
+
+// state shared by every connection the node is serving
+
+pub struct Node {
+
+    // the address we announce to other nodes
+    pub address: String,
+
+    // the network we are part of, the same as the blockchain's
+    pub params: ChainParams,
+
+    pub blockchain: RwLock<Blockchain>,
+
+    // addresses of the nodes we are connected to
+    pub nodes: RwLock<Vec<String>>,
+
+    // outgoing connections we relay new blocks and transactions over,
+    // each one is written by its own task which is fed through this queue
+    pub peers: Mutex<HashMap<String, mpsc::Sender<Arc<Message>>>>,
+
+    pub seen: Mutex<peers::RecentlySeen>,
+
+    // set while we download blocks from other nodes, so only one sync runs at a time
+    pub syncing: AtomicBool,
+}
+
+impl Node {
+
+    fn new(address: String, blockchain: Blockchain) -> Self {
+
+        Node {
+
+            address,
+
+            params: blockchain.params().clone(),
+
+            blockchain: RwLock::new(blockchain),
+
+            nodes: RwLock::new(vec![]),
+
+            peers: Mutex::new(HashMap::new()),
+
+            seen: Mutex::new(peers::RecentlySeen::new()),
+
+            syncing: AtomicBool::new(false),
+        }
+    }
+}
+
+
+// remove transactions older than the network's max mempool transaction age every 30 seconds
+
+async fn cleanup(node: Arc<Node>) {
+
+    let mut cleanup_interval = interval(Duration::from_secs(30));
+
+    loop {
+
+        cleanup_interval.tick().await;
+
+        println!("cleaning the mempool from old transactions");
+
+        node.blockchain.write().await.cleanup_mempool();
+    }
+}
+
+
+// open the blockchain stored in the data directory, a new one is started if there is none
+
+fn load_blockchain(data_dir: &Path, params: ChainParams) -> Result<Blockchain> {
+
+    let blockchain = Blockchain::open(data_dir, params)?;
+
+    println!("loaded blockchain with {} blocks from {}", blockchain.blocks_height(), data_dir.display());
+
+    Ok(blockchain)
+}
+
+
+// mine `count` blocks right here, each one taking the mempool's transactions like a miner would
+// on regtest any hash is almost good enough, so this only takes a moment
+
+fn generate(blockchain: &mut Blockchain, count: u64, public_key: PublicKey) -> Result<()> {
+
+    for _ in 0..count {
+
+        let mut block = blockchain.build_template(public_key.clone(), TemplateLimits::default())?;
+
+        while !block.header.mine(1_000_000) {}
+
+        let hash = block.hash();
+
+        blockchain.add_block(block)?;
+
+        println!("generated block {} at height {}", hash, blockchain.blocks_height() - 1);
+    }
+
+    blockchain.flush()?;
+
+    // what the blocks took is gone from the mempool now
+
+    blockchain.save_mempool()?;
+
+    Ok(())
+}
+
+
+async fn save(node: &Node) {
+
+    let mut blockchain = node.blockchain.write().await;
+
+    match blockchain.flush() {
+
+        Ok(()) => println!("saved blockchain at height {}", blockchain.blocks_height()),
+
+        Err(e) => println!("failed to save blockchain: {}", e),
+    }
+}
+
+
+// keep the pending transactions for the next start, only done on shutdown
+
+async fn save_mempool(node: &Node) {
+
+    let blockchain = node.blockchain.read().await;
+
+    match blockchain.save_mempool() {
+
+        Ok(()) => println!("saved {} mempool transactions", blockchain.mempool().len()),
+
+        Err(e) => println!("failed to save the mempool: {}", e),
+    }
+}
+
+
+// save the blockchain every SAVE_INTERVAL, so a crash loses at most that much
+
+async fn save_periodically(node: Arc<Node>) {
+
+    let mut save_interval = interval(SAVE_INTERVAL);
+
+    // the first tick completes immediately, and there is nothing new to save yet
+    save_interval.tick().await;
+
+    loop {
+
+        save_interval.tick().await;
+
+        save(&node).await;
+    }
+}
+
+
+// resolves once the node is asked to stop, with ctrl-c or (on unix) SIGTERM
+
+async fn shutdown_signal() -> Result<()> {
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+
+        tokio::select! {
+
+            result = tokio::signal::ctrl_c() => result?,
+
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
+}
+
+
+// accept connections until the node stops, a failed accept only affects that one connection
+
+async fn serve(listener: TcpListener, node: Arc<Node>) {
+
+    loop {
+
+        let (socket, peer) = match listener.accept().await {
+
+            Ok(connection) => connection,
+
+            Err(e) => {
+
+                println!("failed to accept a connection: {}", e);
+
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+
+                continue;
+            }
+        };
+
+        println!("accepted connection from {}", peer);
+
+        // every connection gets its own task, so a slow peer can not block the others
+
+        tokio::spawn(handler::handle_connection(socket, node.clone()));
+    }
+}
+
+
+#[tokio::main]
+async fn main() -> Result<()> {
+
+    let cli = Cli::parse();
+
+    let mut params = ChainParams::for_network(cli.network);
+
+    if let Some(megabytes) = cli.max_mempool {
+
+        params.max_mempool_size = megabytes * 1_000_000;
+    }
+
+    let port = cli.port.unwrap_or(params.default_port);
+
+    let address = cli.address.unwrap_or(format!("127.0.0.1:{}", port));
+
+    if let Some(Command::Generate { count, public_key_file }) = cli.command {
+
+        if params.network != Network::Regtest {
+
+            return Err(anyhow!("generate is only available on regtest"));
+        }
+
+        let public_key = PublicKey::load_from_file(&public_key_file)
+            .map_err(|e| anyhow!("failed to read public key: {}", e))?;
+
+        let mut blockchain = load_blockchain(&params.data_dir(&cli.data_dir), params)?;
+
+        return generate(&mut blockchain, count, public_key);
+    }
+
+    let blockchain = load_blockchain(&params.data_dir(&cli.data_dir), params)?;
+
+    let node = Arc::new(Node::new(address, blockchain));
+
+    let addr = format!("0.0.0.0:{}", port);
+
+    let listener = TcpListener::bind(&addr).await?;
+
+    println!("Listening on {} ({})", addr, node.params.network);
+
+    // we start answering right away, the nodes we discover will connect back to us
+
+    let mut server = tokio::spawn(serve(listener, node.clone()));
+
+    tokio::spawn(cleanup(node.clone()));
+
+    tokio::spawn(save_periodically(node.clone()));
+
+    // finding peers and catching up with the rest of the network can take a long time,
+    // so it runs on its own and the node can still be stopped, and saves its progress, meanwhile
+
+    let mut startup = tokio::spawn({
+
+        let node = node.clone();
+
+        async move {
+
+            peers::discover(&node, cli.nodes).await;
+
+            sync::download_blockchain(&node).await;
+        }
+    });
+
+    let mut started = false;
+
+    let shutdown = shutdown_signal();
+
+    tokio::pin!(shutdown);
+
+    // whatever ends the node, the chain and the mempool are saved before we exit
+
+    let result = loop {
+
+        tokio::select! {
+
+            result = &mut server => break result.map_err(|e| anyhow!("server stopped: {}", e)),
+
+            result = &mut startup, if !started => {
+
+                started = true;
+
+                if let Err(e) = result {
+
+                    println!("failed to join the network: {}", e);
+                }
+            }
+
+            result = &mut shutdown => break result,
+        }
+    };
+
+    // a sync still running would keep adding blocks while we save
+
+    startup.abort();
+
+    match &result {
+
+        Ok(()) => println!("shutting down"),
+
+        Err(e) => println!("shutting down: {}", e),
+    }
+
+    save(&node).await;
+
+    save_mempool(&node).await;
+
+    result
 }
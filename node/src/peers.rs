@@ -0,0 +1,222 @@
+use lib::network::Message;
+use lib::sha256::Hash;
+
+use anyhow::{anyhow, Result};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::{timeout, Duration};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+
+use crate::Node;
+
+
+// how many block and transaction hashes we remember for deduplication
+
+const SEEN_CAPACITY: usize = 10_000;
+
+// how long we wait for a peer to answer DiscoverNodes
+
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how long sending a single message to a peer may take before we give up on it
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how many messages may wait for a peer, one that falls further behind is dropped
+
+const PEER_QUEUE_SIZE: usize = 64;
+
+
+// hashes of the blocks and transactions we have recently processed,
+// so a relayed message that comes back to us is not relayed again
+// the oldest hash is forgotten once SEEN_CAPACITY is reached
+
+pub struct RecentlySeen {
+
+    hashes: HashSet<Hash>,
+
+    order: VecDeque<Hash>,
+}
+
+impl RecentlySeen {
+
+    pub fn new() -> Self {
+
+        RecentlySeen {
+
+            hashes: HashSet::new(),
+
+            order: VecDeque::new(),
+        }
+    }
+
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+
+        self.hashes.contains(hash)
+    }
+
+
+    // remember the hash, returns false if we had already seen it
+
+    pub fn insert(&mut self, hash: Hash) -> bool {
+
+        if !self.hashes.insert(hash) {
+
+            return false;
+        }
+
+        self.order.push_back(hash);
+
+        if self.order.len() > SEEN_CAPACITY {
+
+            if let Some(oldest) = self.order.pop_front() {
+
+                self.hashes.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
+
+
+// open a connection to `address`, ask it for the nodes it knows about
+// and tell it where it can reach us
+
+async fn connect(node: &Node, address: &str) -> Result<(TcpStream, Vec<String>)> {
+
+    let mut stream = TcpStream::connect(address).await?;
+
+    Message::DiscoverNodes.send_async(node.params.magic, &mut stream).await?;
+
+    let nodes = match timeout(DISCOVERY_TIMEOUT, Message::recieve_asynce(node.params.magic, &mut stream))
+        .await
+        .map_err(|_| anyhow!("peer did not answer in time"))??
+    {
+        Message::NodeList(nodes) => nodes,
+
+        _ => return Err(anyhow!("unexpected answer to DiscoverNodes")),
+    };
+
+    Message::NodeList(vec![node.address.clone()]).send_async(node.params.magic, &mut stream).await?;
+
+    Ok((stream, nodes))
+}
+
+
+// connect to every node in `addresses`, and to every node they know about in turn,
+// until we are connected to the whole network reachable from them
+
+pub async fn discover(node: &Node, addresses: Vec<String>) {
+
+    let mut queue: VecDeque<String> = addresses.into();
+
+    while let Some(address) = queue.pop_front() {
+
+        if address == node.address || node.peers.lock().await.contains_key(&address) {
+
+            continue;
+        }
+
+        let (stream, nodes) = match connect(node, &address).await {
+
+            Ok(connection) => connection,
+
+            Err(e) => {
+
+                println!("failed to connect to {}: {}", address, e);
+
+                continue;
+            }
+        };
+
+        println!("connected to {}, it knows {} other nodes", address, nodes.len());
+
+        node.peers.lock().await.insert(address.clone(), spawn_writer(node, address.clone(), stream));
+
+        let mut known = node.nodes.write().await;
+
+        if !known.contains(&address) {
+
+            known.push(address);
+        }
+
+        queue.extend(nodes);
+    }
+}
+
+
+// every peer gets its own task writing the messages we relay to it, in order
+// so a slow or stuck peer only holds up itself, the task stops when a send fails or times out
+
+fn spawn_writer(node: &Node, address: String, mut stream: TcpStream) -> mpsc::Sender<Arc<Message>> {
+
+    let (sender, mut receiver) = mpsc::channel::<Arc<Message>>(PEER_QUEUE_SIZE);
+
+    let magic = node.params.magic;
+
+    tokio::spawn(async move {
+
+        while let Some(message) = receiver.recv().await {
+
+            let result = match timeout(SEND_TIMEOUT, message.send_async(magic, &mut stream)).await {
+
+                Ok(result) => result.map_err(|e| e.to_string()),
+
+                Err(_) => Err("timed out".to_string()),
+            };
+
+            if let Err(e) = result {
+
+                println!("failed to relay to {}: {}", address, e);
+
+                return;
+            }
+        }
+    });
+
+    sender
+}
+
+
+// queue a message for every peer we are connected to, without waiting for it to be sent
+// peers whose writer stopped, or that fell too far behind, are forgotten
+
+pub async fn broadcast(node: &Node, message: &Message) {
+
+    let message = Arc::new(message.clone());
+
+    let mut unreachable = vec![];
+
+    {
+        let mut peers = node.peers.lock().await;
+
+        peers.retain(|address, sender| match sender.try_send(message.clone()) {
+
+            Ok(()) => true,
+
+            Err(TrySendError::Full(_)) => {
+
+                println!("{} is not keeping up, disconnecting", address);
+
+                unreachable.push(address.clone());
+
+                false
+            }
+
+            Err(TrySendError::Closed(_)) => {
+
+                unreachable.push(address.clone());
+
+                false
+            }
+        });
+    }
+
+    if !unreachable.is_empty() {
+
+        node.nodes.write().await.retain(|known| !unreachable.contains(known));
+    }
+}
@@ -0,0 +1,219 @@
+use lib::network::Message;
+use lib::types::Block;
+use lib::U256;
+
+use anyhow::{anyhow, Result};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+
+use crate::Node;
+
+
+// how long we wait for a peer to answer before giving up on it
+
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how many blocks we download between two saves of the blockchain,
+// so a sync that is interrupted does not have to start over
+
+const SYNC_FLUSH_INTERVAL: u64 = 500;
+
+
+// send a request to a peer and wait for its answer
+
+async fn request(node: &Node, stream: &mut TcpStream, message: Message) -> Result<Message> {
+
+    let magic = node.params.magic;
+
+    message.send_async(magic, stream).await?;
+
+    let response = timeout(PEER_TIMEOUT, Message::recieve_asynce(magic, stream))
+        .await
+        .map_err(|_| anyhow!("peer did not answer in time"))??;
+
+    Ok(response)
+}
+
+
+// ask every peer we know (except the ones in `skip`) how much work its chain has,
+// and return a connection to the one with the most work, if any has more than we do,
+// together with its work and height
+
+async fn find_best_chain_node(node: &Node, skip: &HashSet<String>) -> Option<(String, TcpStream, U256, u64)> {
+
+    let work = node.blockchain.read().await.chain_work();
+
+    let nodes = node.nodes.read().await.clone();
+
+    let mut best: Option<(String, TcpStream, U256, u64)> = None;
+
+    for address in nodes.into_iter().filter(|address| !skip.contains(address)) {
+
+        let mut stream = match TcpStream::connect(&address).await {
+
+            Ok(stream) => stream,
+
+            Err(e) => {
+
+                println!("failed to connect to {}: {}", address, e);
+
+                continue;
+            }
+        };
+
+        let (peer_work, height) = match request(node, &mut stream, Message::AskChainWork).await {
+
+            Ok(Message::ChainWork(work, height)) => (work, height),
+
+            Ok(_) => {
+
+                println!("{} sent an unexpected answer to AskChainWork", address);
+
+                continue;
+            }
+
+            Err(e) => {
+
+                println!("failed to ask {} for its chain work: {}", address, e);
+
+                continue;
+            }
+        };
+
+        println!("{} has {} blocks and {} work, we have {}", address, height, peer_work, work);
+
+        // a longer chain is not necessarily a better one, what counts is the work that went into it
+
+        if peer_work > work && best.as_ref().is_none_or(|(_, _, best, _)| peer_work > *best) {
+
+            best = Some((address, stream, peer_work, height));
+        }
+    }
+
+    best
+}
+
+
+// ask the peer for the block at `height` of its active chain
+
+async fn fetch_block(node: &Node, stream: &mut TcpStream, height: u64) -> Result<Block> {
+
+    match request(node, stream, Message::FetchBlock(height as usize)).await? {
+
+        Message::NewBlock(block) => Ok(block),
+
+        _ => Err(anyhow!("unexpected answer to FetchBlock({})", height)),
+    }
+}
+
+
+// pull blocks one by one from `stream` until we have every block up to the peer's `peer_height`
+// when a block does not build on one we know, the peer is on another branch, so we keep asking
+// for the blocks before it until we reach one whose parent we know, and add that whole branch
+// add_block keeps it as a side branch until it has more work than ours and then reorganizes
+
+async fn download_from(node: &Node, stream: &mut TcpStream, peer_height: u64) -> Result<()> {
+
+    // the block after our tip, or the peer's tip if its chain is shorter but has more work
+
+    let mut height = node.blockchain.read().await.blocks_height().min(peer_height.saturating_sub(1));
+
+    while height < peer_height {
+
+        let mut branch = vec![fetch_block(node, stream, height).await?];
+
+        let mut fork_height = height;
+
+        while !node.blockchain.read().await.contains_block(&branch[branch.len() - 1].header.prev_block_hash) {
+
+            if fork_height == 0 {
+
+                return Err(anyhow!("the peer's chain does not start with our genesis block"));
+            }
+
+            fork_height -= 1;
+
+            branch.push(fetch_block(node, stream, fork_height).await?);
+        }
+
+        if fork_height < height {
+
+            println!("the peer's chain forks off ours at height {}", fork_height);
+        }
+
+        let mut blockchain = node.blockchain.write().await;
+
+        for block in branch.into_iter().rev() {
+
+            // we may have some of the branch already, as a side branch
+
+            if blockchain.contains_block(&block.hash()) {
+
+                continue;
+            }
+
+            blockchain.add_block(block)?;
+        }
+
+        println!("synced block {}/{}", height + 1, peer_height);
+
+        height += 1;
+
+        drop(blockchain);
+
+        if height % SYNC_FLUSH_INTERVAL == 0 {
+
+            crate::save(node).await;
+        }
+    }
+
+    Ok(())
+}
+
+
+// keep downloading from the peer whose chain has the most work until no peer has more than we do
+// only one sync runs at a time, it is started when the node starts and whenever
+// a block arrives that builds on a block we do not know
+
+pub async fn download_blockchain(node: &Node) {
+
+    if node.syncing.swap(true, Ordering::SeqCst) {
+
+        return;
+    }
+
+    // peers that failed us during this sync, so we do not keep asking them
+
+    let mut failed = HashSet::new();
+
+    while let Some((address, mut stream, work, height)) = find_best_chain_node(node, &failed).await {
+
+        println!("downloading from {}, its chain has more work than ours", address);
+
+        if let Err(e) = download_from(node, &mut stream, height).await {
+
+            println!("sync with {} interrupted: {}", address, e);
+
+            failed.insert(address);
+
+            continue;
+        }
+
+        // a peer that claimed more work than its blocks have would otherwise be asked forever
+
+        if node.blockchain.read().await.chain_work() < work {
+
+            println!("{} claimed more work than its chain has", address);
+
+            failed.insert(address);
+        }
+    }
+
+    println!("sync finished at height {}", node.blockchain.read().await.blocks_height());
+
+    crate::save(node).await;
+
+    node.syncing.store(false, Ordering::SeqCst);
+}